@@ -0,0 +1,74 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{Local, NaiveTime};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Instant};
+
+/// A time of day window with its own rate, `from` and `to` as `HH:MM` in local time. Windows
+/// may wrap past midnight, e.g. `22:00` to `06:00`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Window {
+  from: String,
+  to: String,
+  rate: u64,
+}
+
+/// Bytes per second, unlimited when missing or 0.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct BandwidthConfig {
+  rate: Option<u64>,
+  /// Rates replacing `rate` during their window, the first matching window wins.
+  schedule: Option<Vec<Window>>,
+}
+
+/// Token bucket holding up to one second of transfer at the current rate.
+pub struct Bucket {
+  rate: Option<u64>,
+  schedule: Vec<(NaiveTime, NaiveTime, u64)>,
+  /// Available bytes, negative while downloads owe a wait, and when they were counted.
+  state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+  pub fn new(config: Option<&BandwidthConfig>) -> Self {
+    let time = |v: &str| NaiveTime::parse_from_str(v, "%H:%M")
+      .unwrap_or_else(|_| panic!("Invalid time {v} in bandwidth schedule, expected HH:MM."));
+    let schedule = config.and_then(|c| c.schedule.as_ref())
+      .map(|windows| windows.iter().map(|w| (time(&w.from), time(&w.to), w.rate)).collect())
+      .unwrap_or_default();
+    Self {
+      rate: config.and_then(|c| c.rate),
+      schedule,
+      state: Mutex::new((f64::INFINITY, Instant::now())),
+    }
+  }
+
+  fn rate(&self) -> Option<u64> {
+    let now = Local::now().time();
+    let window = self.schedule.iter().find(|(from, to, _)| if from <= to {
+      *from <= now && now < *to
+    } else {
+      now >= *from || now < *to
+    });
+    window.map(|(_, _, rate)| *rate).or(self.rate).filter(|rate| *rate > 0)
+  }
+
+  /// Takes `n` bytes from the bucket, waiting until the rate allows them.
+  pub async fn take(&self, n: usize) {
+    let Some(rate) = self.rate() else {
+      return;
+    };
+    let rate = rate as f64;
+    let wait = {
+      let mut state = self.state.lock().unwrap();
+      let now = Instant::now();
+      let tokens = (state.0 + now.duration_since(state.1).as_secs_f64() * rate).min(rate) - n as f64;
+      *state = (tokens, now);
+      Duration::from_secs_f64((-tokens / rate).max(0.0))
+    };
+    if !wait.is_zero() {
+      sleep(wait).await;
+    }
+  }
+}
@@ -1,11 +1,16 @@
 use std::{collections::LinkedList, sync::{Arc, OnceLock}};
 
-use reqwest::{Client, Proxy, StatusCode};
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
 use serde::Deserialize;
 use serde_json::{from_value, json, Value};
-use tokio::{sync::Semaphore, time::sleep};
+use tokio::sync::Semaphore;
 
-use super::{Adapters, BoxedFuture, Item, BEARER, USER_AGENT, FIVE_SECOUND};
+use crate::events::{emit, Event};
+use super::http::Http;
+use super::bandwidth::{BandwidthConfig, Bucket};
+use super::scheduler::Scheduler;
+use super::{Adapters, BoxedFuture, Item, MediaKind, Post, PostMedia, Stats, BEARER};
 
 #[derive(Deserialize)]
 struct BlueSkyConfig {
@@ -13,6 +18,7 @@ struct BlueSkyConfig {
   pass: String,
   page_size: Option<i32>,
   concurrency: Option<usize>,
+  bandwidth: Option<BandwidthConfig>,
   path: Option<String>,
 }
 
@@ -30,11 +36,13 @@ pub struct BlueSkyAdapter {
   cache: LinkedList<BlueSkyItem>,
   cursor: Option<String>,
   page_size: i32,
-  client: Client,
+  client: Http,
   sem: Arc<Semaphore>,
+  bandwidth: Arc<Bucket>,
+  scheduler: Arc<Scheduler>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Auth {
   token: String,
   did: String,
@@ -44,24 +52,21 @@ struct Auth {
 pub struct BlueSkyItem {
   pub url: String,
   pub media_url: String,
-  pub client: Client,
-  pub filename: String,
+  pub client: Http,
+  pub post: Arc<Post>,
+  pub index: usize,
+  pub ext: &'static str,
+  pub kind: MediaKind,
   sem: Arc<Semaphore>,
+  bandwidth: Arc<Bucket>,
+  scheduler: Arc<Scheduler>,
 }
 
 impl BlueSkyAdapter {
-  pub fn new(config: Value, proxy: Option<Proxy>) -> Self {
+  pub fn new(config: Value, http: &Http, scheduler: Arc<Scheduler>) -> Self {
     let config: BlueSkyConfig = from_value(config).unwrap();
 
-    let mut builder = Client::builder()
-    .user_agent(USER_AGENT)
-    .gzip(true);
-
-    if let Some(proxy) = proxy.clone() {
-      builder = builder.proxy(proxy);
-    }
-
-    let client = builder.build().unwrap();
+    let client = http.clone();
 
     Self {
       page_size: config.page_size.unwrap_or(50),
@@ -71,10 +76,130 @@ impl BlueSkyAdapter {
       auth: OnceLock::new(),
       cache: LinkedList::new(),
       sem: Arc::new(Semaphore::new(config.concurrency.unwrap_or(50))),
+      bandwidth: Arc::new(Bucket::new(config.bandwidth.as_ref())),
+      scheduler,
       cursor: None,
       client,
     }
   }
+
+  async fn login(&self) -> &Auth {
+    if let Some(auth) = self.auth.get() {
+      return auth;
+    }
+    loop {
+      match async {
+        self.client.post("https://bsky.social/xrpc/com.atproto.server.createSession")
+          .body(json!({
+            "identifier": self.account,
+            "password": self.pass,
+          }).to_string())
+          .header("content-type", "application/json")
+          .send().await?.json::<Value>().await
+      }.await {
+        Ok(json) if json.get("errors").is_some() => {
+          panic!("{} {}", json["errors"].as_str().unwrap(), json["message"].as_str().unwrap());
+        }
+        Ok(json) => {
+          break self.auth.get_or_init(|| Auth {
+            did: json["did"].as_str().unwrap().to_owned(),
+            token: json["accessJwt"].as_str().unwrap().to_owned(),
+            endpoint: json["didDoc"]["service"][0]["serviceEndpoint"].as_str().unwrap().to_string(),
+          });
+        },
+        Err(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
+          self.client.rate_limited(&err).await;
+        }
+        Err(err) if err.is_connect() => {}
+        Err(err) => {
+          message!("Unknown request error {:?}, retrying...", err);
+        }
+      }
+    }
+  }
+
+  fn push_post(&mut self, post: &Value) {
+    let (Some(author), Some(id)) = (post["author"]["handle"].as_str(), post["uri"].as_str().and_then(|v| v.rsplit('/').next())) else {
+      message!("Warning: post {} has no author or uri, skipped.", post["uri"]);
+      return;
+    };
+    let (author, id) = (author.to_owned(), id.to_owned());
+    let mut embed = &post["embed"];
+    if embed["$type"] == "app.bsky.embed.recordWithMedia#view" {
+      embed = &embed["media"];
+    }
+
+    let mut sources = Vec::new();
+    match embed["$type"].as_str() {
+      Some("app.bsky.embed.images#view") => {
+        for image in embed["images"].as_array().into_iter().flatten() {
+          if let Some(url) = image["fullsize"].as_str() {
+            let alt_text = image["alt"].as_str().filter(|v| !v.is_empty()).map(str::to_owned);
+            sources.push((MediaKind::Photo, url.replace("@jpeg", "@png"), "png", alt_text));
+          }
+        }
+      }
+      Some("app.bsky.embed.external#view") => {
+        if let Some(url) = embed["external"]["thumb"].as_str() {
+          sources.push((MediaKind::CardImage, url.replace("@jpeg", "@png"), "png", None));
+        }
+      }
+      Some("app.bsky.embed.record#view") | None => {}
+      Some(embed_type) => {
+        let url = embed["playlist"].as_str().unwrap_or_default().to_owned();
+        sources.push((MediaKind::Unknown(embed_type.to_owned()), url, "bin", None));
+      }
+    }
+
+    let created_at = post["record"]["createdAt"].as_str().or(post["indexedAt"].as_str())
+      .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+      .map(|v| v.to_utc());
+    let hashtags = post["record"]["facets"].as_array().into_iter().flatten()
+      .flat_map(|facet| facet["features"].as_array().into_iter().flatten())
+      .filter(|feature| feature["$type"] == "app.bsky.richtext.facet#tag")
+      .filter_map(|feature| feature["tag"].as_str().map(str::to_owned))
+      .collect();
+    let url = format!("https://bsky.app/profile/{author}/post/{id}");
+    let post = Arc::new(Post {
+      platform: "bluesky",
+      author: author.clone(),
+      author_id: post["author"]["did"].as_str().unwrap_or_default().to_owned(),
+      author_name: post["author"]["displayName"].as_str().unwrap_or_default().to_owned(),
+      id,
+      url: url.clone(),
+      created_at,
+      text: post["record"]["text"].as_str().unwrap_or_default().to_owned(),
+      hashtags,
+      stats: Stats {
+        likes: post["likeCount"].as_u64(),
+        reposts: post["repostCount"].as_u64(),
+        replies: post["replyCount"].as_u64(),
+        quotes: post["quoteCount"].as_u64(),
+      },
+      media: sources.iter().enumerate().map(|(index, (kind, url, _, alt_text))| PostMedia {
+        index: index + 1,
+        kind: kind.name(),
+        url: url.clone(),
+        alt_text: alt_text.clone(),
+      }).collect(),
+      raw: post.clone(),
+    });
+    for (index, (kind, media_url, ext, _)) in sources.into_iter().enumerate() {
+      let index = index + 1;
+      self.cache.push_back(BlueSkyItem {
+        url: url.clone(),
+        media_url,
+        client: self.client.clone(),
+        post: post.clone(),
+        index,
+        ext,
+        kind,
+        sem: self.sem.clone(),
+        bandwidth: self.bandwidth.clone(),
+        scheduler: self.scheduler.clone(),
+      });
+    }
+  }
 }
 
 impl Adapters for BlueSkyAdapter {
@@ -97,43 +222,10 @@ impl Adapters for BlueSkyAdapter {
         return Some(Box::new(item) as Box<dyn Item>);
       }
 
-      let Auth { token, did, endpoint} = if let Some(auth) = self.auth.get() {
-        auth
-      } else {
-        loop {
-          match async {
-            self.client.post("https://bsky.social/xrpc/com.atproto.server.createSession")
-              .body(json!({
-                "identifier": self.account,
-                "password": self.pass,
-              }).to_string())
-              .header("content-type", "application/json")
-              .send().await?.json::<Value>().await
-          }.await {
-            Ok(json) if json.get("errors").is_some() => {
-              panic!("{} {}", json["errors"].as_str().unwrap(), json["message"].as_str().unwrap());
-            }
-            Ok(json) => {
-              break self.auth.get_or_init(|| Auth {
-                did: json["did"].as_str().unwrap().to_owned(),
-                token: json["accessJwt"].as_str().unwrap().to_owned(),
-                endpoint: json["didDoc"]["service"][0]["serviceEndpoint"].as_str().unwrap().to_string(),
-              });
-            },
-            Err(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
-              println!("Warning: too many request, sleep 5 secs and retrying...");
-              sleep(FIVE_SECOUND).await;
-            }
-            Err(err) if err.is_connect() => {}
-            Err(err) => {
-              println!("Unknown request error {:?}, retrying...", err);
-            }
-          }
-        }
-      };
-      
+      let Auth { token, did, endpoint } = self.login().await.clone();
+
       let mut query = vec![
-        ("actor", did.clone()),
+        ("actor", did),
         ("limit", self.page_size.to_string()),
       ];
 
@@ -146,19 +238,18 @@ impl Adapters for BlueSkyAdapter {
         let json = loop {
           match async {
             self.client.get(endpoint.to_owned() + "/xrpc/app.bsky.feed.getActorLikes")
-              .header("authorization", BEARER.to_owned() + token)
+              .header("authorization", BEARER.to_owned() + &token)
               .header("content-type", "application/json")
               .query(&query)
               .send().await?.error_for_status()?.json::<GetActorLikes>().await
           }.await {
             Ok(json) => break json,
             Err(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
-              println!("Warning: too many request, sleep 5 secs and retrying...");
-              sleep(FIVE_SECOUND).await;
+              self.client.rate_limited(&err).await;
             }
             Err(err) if err.is_connect() => {}
             Err(err) => {
-              println!("Unknown request error {:?}, retrying...", err);
+              message!("Unknown request error {:?}, retrying...", err);
             }
           }
         };
@@ -170,38 +261,78 @@ impl Adapters for BlueSkyAdapter {
           query.push(("cursor", json.cursor));
         }
       };
-      if likes.is_none() {
-        return None;
-      }
-      let likes = likes.unwrap();
+      let likes = likes?;
       
       for post in likes.feed {
-        let author = post["post"]["author"]["handle"].as_str().unwrap().to_owned();
-        let id = post["post"]["uri"].as_str().unwrap().split("/").last().unwrap().to_owned();
-        if let Some(embed) = post["post"].get("embed") {
-          for (index, image) in embed["images"].as_array().unwrap().iter().enumerate() {
-            let index = index + 1;
-            let url = image["fullsize"].as_str().unwrap().to_owned();
-            self.cache.push_back(BlueSkyItem {
-              url: format!("https://bsky.app/profile/{author}/post/{id}"),
-              media_url: url.replace("@jpeg", "@png"),
-              client: self.client.clone(),
-              filename: format!("{author} {id} {index}.png"),
-              sem: self.sem.clone(),
-            });
-          }
-        }
+        self.push_post(&post["post"]);
       }
+      emit(Event::PageFetched { platform: self.platform(), account: &self.account, items: self.cache.len() });
 
       self.cursor = Some(likes.cursor);
       self.cache.pop_front().map(|v| Box::new(v) as Box<dyn Item>)
     })
   }
+
+  fn post(&mut self, id: &str) -> BoxedFuture<'_, Vec<Box<dyn Item>>> {
+    let uri = id.to_owned();
+    Box::pin(async move {
+      let Auth { token, endpoint, .. } = self.login().await.clone();
+      let json = loop {
+        match async {
+          self.client.get(endpoint.to_owned() + "/xrpc/app.bsky.feed.getPostThread")
+            .header("authorization", BEARER.to_owned() + &token)
+            .query(&[("uri", uri.as_str()), ("depth", "0"), ("parentHeight", "0")])
+            .send().await?.error_for_status()?.json::<Value>().await
+        }.await {
+          Ok(json) => break json,
+          Err(err) if err.status() == Some(StatusCode::BAD_REQUEST) => {
+            message!("Warning: post {uri} not found, skipped.");
+            return Vec::new();
+          }
+          Err(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
+            self.client.rate_limited(&err).await;
+          }
+          Err(err) if err.is_connect() => {}
+          Err(err) => {
+            message!("Unknown request error {:?}, retrying...", err);
+          }
+        }
+      };
+
+      let thread = &json["thread"];
+      match thread["$type"].as_str() {
+        Some("app.bsky.feed.defs#blockedPost") => {
+          message!("Warning: post {uri} is blocked, skipped.");
+          return Vec::new();
+        }
+        Some("app.bsky.feed.defs#notFoundPost") => {
+          message!("Warning: post {uri} not found, skipped.");
+          return Vec::new();
+        }
+        _ if !thread["post"].is_object() => {
+          message!("Warning: post {uri} is unavailable, skipped.");
+          return Vec::new();
+        }
+        _ => {}
+      }
+      let len = self.cache.len();
+      self.push_post(&thread["post"]);
+      self.cache.split_off(len).into_iter().map(|v| Box::new(v) as Box<dyn Item>).collect()
+    })
+  }
 }
 
 impl Item for BlueSkyItem {
-  fn filename(&self) -> &str {
-    &self.filename
+  fn post(&self) -> &Post {
+    &self.post
+  }
+
+  fn index(&self) -> usize {
+    self.index
+  }
+
+  fn ext(&self) -> &str {
+    self.ext
   }
   
   fn url(&self) -> &str {
@@ -211,23 +342,34 @@ impl Item for BlueSkyItem {
   fn media_url(&self) -> &str {
     &self.media_url
   }
+
+  fn kind(&self) -> &MediaKind {
+    &self.kind
+  }
+
+  fn alt_text(&self) -> Option<&str> {
+    self.post.media.get(self.index - 1).and_then(|m| m.alt_text.as_deref())
+  }
+
+  fn post_text(&self) -> &str {
+    &self.post.text
+  }
+
+  fn timestamp(&self) -> Option<DateTime<Utc>> {
+    self.post.created_at
+  }
   
   fn get(&self) -> BoxedFuture<'_, bytes::Bytes> {
     Box::pin(async {
-      let _guard = self.sem.acquire().await.unwrap();
-      loop {
-        match self.client.get(&self.media_url).send().await.and_then(|r| r.error_for_status()){
-          Ok(res) => return res.bytes().await.unwrap(),
-          Err(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
-            println!("Warning: too many request, sleep 5 secs and retrying...");
-            sleep(FIVE_SECOUND).await;
-          }
-          Err(err) if err.is_connect() => {}
-          Err(err) => {
-            println!("Unknown request error {:?}, retrying...", err);
-          }
-        }
-      };
+      let permit = self.scheduler.acquire(&self.sem, &self.bandwidth, &self.media_url).await;
+      self.client.download(&self.media_url, &permit).await
+    })
+  }
+
+  fn size(&self) -> BoxedFuture<'_, Option<u64>> {
+    Box::pin(async {
+      let _permit = self.scheduler.acquire(&self.sem, &self.bandwidth, &self.media_url).await;
+      self.client.size(&self.media_url).await
     })
   }
 }
\ No newline at end of file
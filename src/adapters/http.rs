@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use reqwest::header::{HeaderMap, CONTENT_RANGE, RANGE};
+use reqwest::{tls, Client, IntoUrl, NoProxy, Proxy, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_value, Value};
+use tokio::time::sleep;
+
+use super::scheduler::Permit;
+use super::{FIVE_SECOUND, USER_AGENT};
+use crate::events::{emit, Event};
+use crate::proxy::Resolved;
+
+/// Settings of the HTTP client every account shares.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct HttpConfig {
+  /// Uses HTTP/2 where the server offers it, default is true.
+  http2: Option<bool>,
+  /// Lowest accepted TLS version, "1.2" or "1.3", default is left to the TLS library.
+  tls_min_version: Option<String>,
+  /// Idle connections kept open per host, default is unlimited.
+  pool_max_idle_per_host: Option<usize>,
+  /// Seconds to open a connection, default is 10.
+  connect_timeout: Option<u64>,
+  /// Seconds without receiving any data before a request counts as stalled, default is 30.
+  read_timeout: Option<u64>,
+  /// Seconds a whole request may take, default is unlimited. Downloads cut by it resume where
+  /// they stopped.
+  timeout: Option<u64>,
+}
+
+/// A proxy URL, `direct` to bypass every proxy, or a list of both.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProxySetting {
+  One(String),
+  List(Vec<String>),
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+  /// Moves to the next proxy when a request is rate limited.
+  #[default]
+  OnRateLimit,
+  /// Moves to the next proxy on every request.
+  RoundRobin,
+}
+
+#[derive(Deserialize)]
+struct AccountProxy {
+  proxy: Option<ProxySetting>,
+  proxy_rotation: Option<Rotation>,
+}
+
+/// A connection pool per proxy, shared by adapters, with default headers of each adapter on top.
+#[derive(Clone)]
+pub struct Http {
+  clients: Arc<[Client]>,
+  next: Arc<AtomicUsize>,
+  rotation: Rotation,
+  headers: HeaderMap,
+}
+
+impl Http {
+  /// A client per entry of `proxies`, where `direct` means none, and a direct one without any
+  /// entry. Hosts in `no_proxy` are always reached directly.
+  pub fn new(config: &HttpConfig, proxies: &[String], rotation: Rotation, no_proxy: Option<&str>) -> Self {
+    let clients: Vec<_> = if proxies.is_empty() {
+      vec![client(config, "direct", None)]
+    } else {
+      proxies.iter().map(|proxy| client(config, proxy, no_proxy)).collect()
+    };
+    Self { clients: clients.into(), next: Arc::new(AtomicUsize::new(0)), rotation, headers: HeaderMap::new() }
+  }
+
+  fn client(&self) -> &Client {
+    let next = match self.rotation {
+      Rotation::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+      Rotation::OnRateLimit => self.next.load(Ordering::Relaxed),
+    };
+    &self.clients[next % self.clients.len()]
+  }
+
+  /// Reports a 429 answer to `err`, switches to the next proxy when there is more than one, and
+  /// waits before the retry.
+  pub async fn rate_limited(&self, err: &reqwest::Error) {
+    message!("Warning: too many request, sleep 5 secs and retrying...");
+    emit(Event::RateLimited { url: err.url().map_or("", |v| v.as_str()), wait_secs: FIVE_SECOUND.as_secs() });
+    if self.clients.len() > 1 && self.rotation == Rotation::OnRateLimit {
+      self.next.fetch_add(1, Ordering::Relaxed);
+    }
+    sleep(FIVE_SECOUND).await;
+  }
+
+  /// The same connection pools, sending `headers` with every request.
+  pub fn with_headers(&self, headers: HeaderMap) -> Self {
+    Self { headers, ..self.clone() }
+  }
+
+  pub fn get<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+    self.client().get(url).headers(self.headers.clone())
+  }
+
+  pub fn post<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+    self.client().post(url).headers(self.headers.clone())
+  }
+
+  /// `Content-Length` of `url` from a HEAD request.
+  pub async fn size(&self, url: &str) -> Option<u64> {
+    let res = self.client().head(url).headers(self.headers.clone()).send().await.ok()?;
+    res.error_for_status().ok()?.content_length()
+  }
+
+  /// Downloads `url` within `permit`. A failed or stalled body is retried with a Range request
+  /// from where it stopped, and from the start when the server ignores the range. Client errors
+  /// such as a deleted media give up with an empty body.
+  pub async fn download(&self, url: &str, permit: &Permit<'_>) -> Bytes {
+    let mut bytes = BytesMut::new();
+    let mut length = None;
+    loop {
+      let mut req = self.get(url);
+      if !bytes.is_empty() {
+        req = req.header(RANGE, format!("bytes={}-", bytes.len()));
+      }
+      match req.send().await.and_then(|r| r.error_for_status()) {
+        Ok(res) => {
+          if res.status() == StatusCode::PARTIAL_CONTENT {
+            length = length.or_else(|| total_length(&res));
+          } else {
+            bytes.clear();
+            length = res.content_length();
+          }
+          match permit.read(res, &mut bytes).await {
+            Ok(()) if length.is_none_or(|v| bytes.len() as u64 >= v) => return bytes.freeze(),
+            Ok(()) => {}
+            Err(err) if err.is_timeout() => {
+              message!("Warning: download of {} stalled at {} bytes, resuming...", url, bytes.len());
+            }
+            Err(err) => message!("IO error {:?}, retrying...", err),
+          }
+        }
+        Err(err) if err.status() == Some(StatusCode::RANGE_NOT_SATISFIABLE) => bytes.clear(),
+        Err(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
+          self.rate_limited(&err).await;
+        }
+        Err(err) if err.status().is_some_and(|v| v.is_client_error()) => {
+          message!("Warning: {} answered {}, skipped.", url, err.status().unwrap());
+          return Bytes::new();
+        }
+        Err(err) if err.status().is_some_and(|v| v.is_server_error()) => {
+          message!("Warning: {} answered {}, sleep 5 secs and retrying...", url, err.status().unwrap());
+          sleep(FIVE_SECOUND).await;
+        }
+        Err(err) if err.is_connect() => {}
+        Err(err) if err.is_timeout() => message!("Warning: request to {} timed out, retrying...", url),
+        Err(err) => {
+          message!("Unknown request error {:?}, sleep 5 secs and retrying...", err);
+          sleep(FIVE_SECOUND).await;
+        }
+      }
+    }
+  }
+}
+
+fn client(config: &HttpConfig, proxy: &str, no_proxy: Option<&str>) -> Client {
+  let mut builder = Client::builder()
+  .user_agent(USER_AGENT)
+  .gzip(true);
+
+  if !config.http2.unwrap_or(true) {
+    builder = builder.http1_only();
+  }
+  match config.tls_min_version.as_deref() {
+    Some("1.2") => builder = builder.min_tls_version(tls::Version::TLS_1_2),
+    Some("1.3") => builder = builder.min_tls_version(tls::Version::TLS_1_3),
+    Some(version) => panic!("Unknown TLS version {version}, expected \"1.2\" or \"1.3\"."),
+    None => {}
+  }
+  builder = builder
+  .connect_timeout(Duration::from_secs(config.connect_timeout.unwrap_or(10)))
+  .read_timeout(Duration::from_secs(config.read_timeout.unwrap_or(30)));
+  if let Some(timeout) = config.timeout.filter(|v| *v > 0) {
+    builder = builder.timeout(Duration::from_secs(timeout));
+  }
+  if let Some(max) = config.pool_max_idle_per_host {
+    builder = builder.pool_max_idle_per_host(max);
+  }
+  if proxy == "direct" {
+    builder = builder.no_proxy();
+  } else {
+    let proxy = Proxy::all(proxy).unwrap_or_else(|_| panic!("Invalid proxy {proxy}."));
+    builder = builder.proxy(proxy.no_proxy(no_proxy.and_then(NoProxy::from_string)));
+  }
+
+  builder.build().unwrap()
+}
+
+/// The clients of all accounts, shared between accounts with the same proxies.
+pub struct HttpPool {
+  config: HttpConfig,
+  /// Proxy of accounts without their own.
+  default: Option<Resolved>,
+  pools: HashMap<(Vec<String>, Rotation, Option<String>), Http>,
+}
+
+impl HttpPool {
+  pub fn new(config: HttpConfig, default: Option<Resolved>) -> Self {
+    Self { config, default, pools: HashMap::new() }
+  }
+
+  /// The client for the `proxy` and `proxy_rotation` of an account config.
+  pub fn account(&mut self, account: &Value) -> Http {
+    let AccountProxy { proxy, proxy_rotation } = from_value(account.clone()).unwrap();
+    let (proxies, no_proxy) = match (proxy, &self.default) {
+      (Some(ProxySetting::One(proxy)), _) => (vec![proxy], None),
+      (Some(ProxySetting::List(proxies)), _) => (proxies, None),
+      (None, Some(default)) => (vec![default.url.clone()], default.no_proxy.clone()),
+      (None, None) => (vec![], None),
+    };
+    let rotation = proxy_rotation.unwrap_or_default();
+    self.pools
+      .entry((proxies, rotation, no_proxy))
+      .or_insert_with_key(|(proxies, rotation, no_proxy)| Http::new(&self.config, proxies, *rotation, no_proxy.as_deref()))
+      .clone()
+  }
+}
+
+/// The full size from `Content-Range: bytes 100-199/200`.
+fn total_length(res: &Response) -> Option<u64> {
+  res.headers().get(CONTENT_RANGE)?.to_str().ok()?.rsplit_once('/')?.1.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio::net::TcpListener;
+  use tokio::sync::Semaphore;
+
+  use super::*;
+  use crate::adapters::bandwidth::Bucket;
+  use crate::adapters::scheduler::Scheduler;
+
+  #[tokio::test]
+  async fn download_gives_up_on_client_errors() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/deleted.jpg", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut buf = [0; 1024];
+      let _ = stream.read(&mut buf).await.unwrap();
+      stream.write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+    });
+
+    let http = Http::new(&HttpConfig::default(), &[], Rotation::default(), None);
+    let scheduler = Scheduler::new(Default::default(), None);
+    let (account, bandwidth) = (Semaphore::new(1), Bucket::new(None));
+    let permit = scheduler.acquire(&account, &bandwidth, &url).await;
+    assert!(http.download(&url, &permit).await.is_empty());
+  }
+}
@@ -0,0 +1,99 @@
+use std::collections::LinkedList;
+
+use serde::Deserialize;
+use serde_json::{from_value, Value};
+use tokio::io::AsyncReadExt;
+
+use super::{parse_post_url, Adapters, BoxedFuture, Item, SharedAdapter};
+
+#[derive(Deserialize)]
+struct ImportConfig {
+  /// A text file with one post URL per line, `-` reads from stdin.
+  file: String,
+  path: Option<String>,
+}
+
+/// Downloads the posts listed in a file, each URL is handed to the resolver of its platform.
+pub struct ImportAdapter {
+  path: String,
+  file: String,
+  /// Unread lines with their line numbers, `None` until the source is read.
+  lines: Option<LinkedList<(usize, String)>>,
+  /// The adapters of the other accounts by platform.
+  resolvers: Vec<(&'static str, SharedAdapter)>,
+  cache: LinkedList<Box<dyn Item>>,
+}
+
+impl ImportAdapter {
+  pub fn new(config: Value, resolvers: Vec<(&'static str, SharedAdapter)>) -> Self {
+    let config: ImportConfig = from_value(config).unwrap();
+    Self {
+      path: config.path.unwrap_or("./import".to_owned()),
+      file: config.file,
+      lines: None,
+      resolvers,
+      cache: LinkedList::new(),
+    }
+  }
+
+  async fn read(&self) -> LinkedList<(usize, String)> {
+    let result = if self.file == "-" {
+      let mut text = String::new();
+      tokio::io::stdin().read_to_string(&mut text).await.map(|_| text)
+    } else {
+      tokio::fs::read_to_string(&self.file).await
+    };
+    let text = result.unwrap_or_else(|err| {
+      message!("Cannot read {}, {}, skipped.", self.file, err);
+      String::new()
+    });
+    text.lines().enumerate().map(|(n, line)| (n + 1, line.trim().to_owned())).collect()
+  }
+}
+
+impl Adapters for ImportAdapter {
+  fn platform(&self) -> &'static str {
+    "import"
+  }
+
+  fn name(&self) -> &str {
+    &self.file
+  }
+
+  fn path(&self) -> &str {
+    &self.path
+  }
+
+  fn next(&mut self) -> BoxedFuture<'_, Option<Box<dyn Item>>> {
+    Box::pin(async {
+      if self.lines.is_none() {
+        self.lines = Some(self.read().await);
+      }
+
+      while self.cache.is_empty() {
+        let (n, line) = self.lines.as_mut().unwrap().pop_front()?;
+        if line.is_empty() || line.starts_with('#') {
+          continue;
+        }
+        let Some((platform, id)) = parse_post_url(&line) else {
+          message!("Line {n}: unknown post url {line}, skipped.");
+          continue;
+        };
+        let Some((_, resolver)) = self.resolvers.iter().find(|(v, _)| *v == platform) else {
+          message!("Line {n}: no {platform} account configured for {line}, skipped.");
+          continue;
+        };
+        let items = resolver.lock().await.post(&id).await;
+        if items.is_empty() {
+          message!("Line {n}: no media in {line}, skipped.");
+        }
+        self.cache.extend(items);
+      }
+      self.cache.pop_front()
+    })
+  }
+
+  fn post(&mut self, _id: &str) -> BoxedFuture<'_, Vec<Box<dyn Item>>> {
+    Box::pin(async { Vec::new() })
+  }
+}
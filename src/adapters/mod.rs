@@ -1,26 +1,132 @@
-use std::{future::Future, pin::Pin, time::Duration};
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
 
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::Mutex;
+pub mod bandwidth;
 pub mod twitter;
 pub mod bluesky;
+pub mod http;
+pub mod import;
+pub mod scheduler;
 
 pub type BoxedFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a + Send>>;
-pub const USER_AGENT: &'static str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.1 Safari/605.1.15";
+/// An adapter used by its own account and by imports, so both share its limits and session.
+pub type SharedAdapter = Arc<Mutex<Box<dyn Adapters + Send>>>;
+pub const USER_AGENT: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.1 Safari/605.1.15";
 pub const FIVE_SECOUND: Duration = Duration::from_secs(5);
-pub const BEARER: &'static str = "Bearer ";
+pub const BEARER: &str = "Bearer ";
 
 pub trait Adapters: Send + Sync {
   fn platform(&self) -> &'static str;
   fn path(&self) -> &str;
   fn name(&self) -> &str;
   fn next(&mut self) -> BoxedFuture<'_, Option<Box<dyn Item>>>;
+  /// Fetches the media of a single post, `id` is the one returned by [`parse_post_url`].
+  fn post(&mut self, id: &str) -> BoxedFuture<'_, Vec<Box<dyn Item>>>;
+}
+
+/// Recognises the web URL of a post, returning the platform and the id its adapter looks it up by.
+pub fn parse_post_url(url: &str) -> Option<(&'static str, String)> {
+  let url = url.trim();
+  let url = url.split_once("://").map(|(_, v)| v).unwrap_or(url);
+  let url = url.split(['?', '#']).next()?;
+  let mut segments = url.split('/').filter(|v| !v.is_empty());
+  let host = segments.next()?.to_lowercase();
+  let host = host.strip_prefix("www.").or(host.strip_prefix("mobile.")).unwrap_or(&host);
+  let segments: Vec<_> = segments.collect();
+
+  match host {
+    "x.com" | "twitter.com" => {
+      let index = segments.iter().position(|v| *v == "status")?;
+      let id = segments.get(index + 1)?;
+      id.bytes().all(|b| b.is_ascii_digit()).then(|| ("twitter", id.to_string()))
+    }
+    "bsky.app" => match segments.as_slice() {
+      ["profile", handle, "post", rkey, ..] => Some(("bluesky", format!("at://{handle}/app.bsky.feed.post/{rkey}"))),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum MediaKind {
+  Photo,
+  Video,
+  Gif,
+  Audio,
+  CardImage,
+  /// A media type the adapter does not know how to download, with the platform's own name for it.
+  Unknown(String),
+}
+
+impl MediaKind {
+  /// The name used for this kind in config and manifest.
+  pub fn name(&self) -> &'static str {
+    match self {
+      MediaKind::Photo => "photo",
+      MediaKind::Video => "video",
+      MediaKind::Gif => "gif",
+      MediaKind::Audio => "audio",
+      MediaKind::CardImage => "card_image",
+      MediaKind::Unknown(_) => "unknown",
+    }
+  }
+}
+
+/// The post an item comes from, shared by every media of the post.
+pub struct Post {
+  pub platform: &'static str,
+  /// Screen name on Twitter, handle on Bluesky.
+  pub author: String,
+  pub author_id: String,
+  pub author_name: String,
+  pub id: String,
+  pub url: String,
+  pub created_at: Option<DateTime<Utc>>,
+  pub text: String,
+  pub hashtags: Vec<String>,
+  pub stats: Stats,
+  pub media: Vec<PostMedia>,
+  /// The post object as the API returned it.
+  pub raw: Value,
+}
+
+#[derive(Serialize, Default)]
+pub struct Stats {
+  pub likes: Option<u64>,
+  pub reposts: Option<u64>,
+  pub replies: Option<u64>,
+  pub quotes: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct PostMedia {
+  pub index: usize,
+  pub kind: &'static str,
+  pub url: String,
+  pub alt_text: Option<String>,
 }
 
 pub trait Item: Send + Sync {
-  fn filename(&self) -> &str;
+  fn post(&self) -> &Post;
+  /// Position of the media in its post, starting from 1.
+  fn index(&self) -> usize;
+  fn ext(&self) -> &str;
   fn url(&self) -> &str;
   fn media_url(&self) -> &str;
+  fn kind(&self) -> &MediaKind;
+  /// Accessibility description of the media, if the author wrote one.
+  fn alt_text(&self) -> Option<&str>;
+  fn post_text(&self) -> &str;
+  /// When the post was published, applied as the modification time of the saved file.
+  fn timestamp(&self) -> Option<DateTime<Utc>>;
   fn get(&self) -> BoxedFuture<'_, Bytes>;
+  /// Size of the media from a HEAD request, if the server tells it.
+  fn size(&self) -> BoxedFuture<'_, Option<u64>>;
 }
 
 #[macro_export]
@@ -28,4 +134,30 @@ macro_rules! insert {
   ($h:expr, $($k:literal, $v:expr),*) => {
     $($h.insert($k, HeaderValue::from_str(($v).as_str()).unwrap());)*
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_twitter_urls() {
+    let id = Some(("twitter", "123".to_owned()));
+    assert_eq!(parse_post_url("https://x.com/a/status/123"), id);
+    assert_eq!(parse_post_url("https://mobile.twitter.com/a/status/123"), id);
+    assert_eq!(parse_post_url("https://www.x.com/a/status/123/photo/1"), id);
+    assert_eq!(parse_post_url("https://twitter.com/i/web/status/123"), id);
+    assert_eq!(parse_post_url(" x.com/a/status/123?s=20&t=abc#m "), id);
+    assert_eq!(parse_post_url("https://x.com/a/status/12a3"), None);
+    assert_eq!(parse_post_url("https://x.com/a/likes"), None);
+  }
+
+  #[test]
+  fn parses_bluesky_urls() {
+    let id = Some(("bluesky", "at://a.bsky.social/app.bsky.feed.post/3k".to_owned()));
+    assert_eq!(parse_post_url("https://bsky.app/profile/a.bsky.social/post/3k"), id);
+    assert_eq!(parse_post_url("https://bsky.app/profile/a.bsky.social/post/3k?ref=x"), id);
+    assert_eq!(parse_post_url("https://bsky.app/profile/a.bsky.social"), None);
+    assert_eq!(parse_post_url("https://example.com/profile/a/post/3k"), None);
+  }
+}
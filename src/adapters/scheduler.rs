@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+use reqwest::{Response, Url};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+
+use super::bandwidth::{BandwidthConfig, Bucket};
+
+/// Concurrent downloads over all accounts, unlimited by default.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct LimitsConfig {
+  total: Option<usize>,
+  /// Limit for every host not in `hosts`.
+  per_host: Option<usize>,
+  hosts: Option<HashMap<String, usize>>,
+}
+
+/// Shared by every adapter, so accounts on the same CDN do not add up their `concurrency`.
+pub struct Scheduler {
+  total: Semaphore,
+  bandwidth: Bucket,
+  config: LimitsConfig,
+  hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+/// Held while a download runs, releases every slot it took on drop.
+pub struct Permit<'a> {
+  _account: SemaphorePermit<'a>,
+  _host: Option<OwnedSemaphorePermit>,
+  _total: SemaphorePermit<'a>,
+  buckets: [&'a Bucket; 2],
+}
+
+impl Scheduler {
+  pub fn new(config: LimitsConfig, bandwidth: Option<&BandwidthConfig>) -> Self {
+    Self {
+      total: Semaphore::new(config.total.unwrap_or(Semaphore::MAX_PERMITS).max(1)),
+      bandwidth: Bucket::new(bandwidth),
+      config,
+      hosts: Mutex::new(HashMap::new()),
+    }
+  }
+
+  fn host(&self, url: &str) -> Option<Arc<Semaphore>> {
+    let host = Url::parse(url).ok()?.host_str()?.to_owned();
+    let limit = self.config.hosts.as_ref()
+      .and_then(|hosts| hosts.get(&host).copied())
+      .or(self.config.per_host)?;
+    let mut hosts = self.hosts.lock().unwrap();
+    Some(hosts.entry(host).or_insert_with(|| Arc::new(Semaphore::new(limit.max(1)))).clone())
+  }
+
+  /// Waits for a slot of the account, then of the host of `url`, then of the total.
+  pub async fn acquire<'a>(&'a self, account: &'a Semaphore, bandwidth: &'a Bucket, url: &str) -> Permit<'a> {
+    let _account = account.acquire().await.unwrap();
+    let _host = match self.host(url) {
+      Some(host) => Some(host.acquire_owned().await.unwrap()),
+      None => None,
+    };
+    let _total = self.total.acquire().await.unwrap();
+    Permit { _account, _host, _total, buckets: [&self.bandwidth, bandwidth] }
+  }
+}
+
+impl Permit<'_> {
+  /// Streams the body of `res` into `bytes` within the global and account bandwidth. What was
+  /// read stays in `bytes` when the body fails half way.
+  pub async fn read(&self, mut res: Response, bytes: &mut BytesMut) -> reqwest::Result<()> {
+    while let Some(chunk) = res.chunk().await? {
+      for bucket in self.buckets {
+        bucket.take(chunk.len()).await;
+      }
+      bytes.extend_from_slice(&chunk);
+    }
+    Ok(())
+  }
+}
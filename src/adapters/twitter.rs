@@ -1,18 +1,27 @@
-use std::cell::OnceCell;
 use std::collections::LinkedList;
 use std::path::Path;
-use std::sync::{Arc, LazyLock, OnceLock};
-use bytes::{Bytes, BytesMut};
-use reqwest::{Client, Proxy, StatusCode};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use reqwest::{RequestBuilder, StatusCode};
 use reqwest::header::{HeaderMap, HeaderValue};
 use serde::Deserialize;
 use serde_json::{from_value, json, to_string_pretty, Value};
 use tokio::io::AsyncWriteExt;
 use tokio::sync::Semaphore;
 use tokio::time::sleep;
+use crate::events::{emit, Event};
 use crate::insert;
 
-use super::{Adapters, BoxedFuture, Item, USER_AGENT, FIVE_SECOUND};
+use hls::Hls;
+use model::{Media, Tweet, TweetResult};
+use super::http::Http;
+use super::bandwidth::{BandwidthConfig, Bucket};
+use super::scheduler::Scheduler;
+use super::{Adapters, BoxedFuture, Item, MediaKind, Post, PostMedia, FIVE_SECOUND};
+
+mod hls;
+mod model;
 
 static USER_FEATUREL: LazyLock<String> = LazyLock::new(|| {
   json!({
@@ -51,6 +60,9 @@ static TWEET_FEATURE: LazyLock<String> = LazyLock::new(|| {
   }).to_string()
 });
 
+/// The bearer token of the web client, used when no `authorization` is configured.
+const GUEST_BEARER: &str = "Bearer AAAAAAAAAAAAAAAAAAAAANRILgAAAAAAnNwIzUejRCOuH5E6I8xnZz4puTs%3D1Zv7ttfk8LF81IUq16cHjhLTvJu4FA33AGWWjCpTnA";
+
 #[inline(always)]
 fn tweet_variables(user_id: &str, cursor: &Value, page_size: i32) -> String {
   json!({
@@ -70,16 +82,108 @@ fn tweet_variables(user_id: &str, cursor: &Value, page_size: i32) -> String {
   }).to_string()
 }
 
+/// Which rendition of a video to download, `"max"`, `"min"` or `{ "cap": <bits per second> }`.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoQuality {
+  #[default]
+  Max,
+  Min,
+  Cap(u64),
+}
+
+impl VideoQuality {
+  /// Picks one of `items` by the bitrate returned from `bitrate`. A cap falls back to the
+  /// lowest bitrate when every item is above it.
+  fn pick<T>(self, items: impl IntoIterator<Item = T>, bitrate: impl Fn(&T) -> u64) -> Option<T> {
+    let mut items: Vec<T> = items.into_iter().collect();
+    items.sort_by_key(|v| bitrate(v));
+    match self {
+      VideoQuality::Max => items.pop(),
+      VideoQuality::Min => items.into_iter().next(),
+      VideoQuality::Cap(cap) => {
+        let index = items.iter().rposition(|v| bitrate(v) <= cap).unwrap_or(0);
+        items.into_iter().nth(index)
+      }
+    }
+  }
+}
+
+/// The timeline of `user_name` to download, the likes timeline needs a logged-in session.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Timeline {
+  #[default]
+  Likes,
+  Media,
+}
+
+impl Timeline {
+  fn endpoint(self) -> &'static str {
+    match self {
+      Timeline::Likes => "https://api.twitter.com/graphql/QK8AVO3RpcnbLPKXLAiVog/Likes",
+      Timeline::Media => "https://api.twitter.com/graphql/vFPc2LVIu7so2uA_gHQAdg/UserMedia",
+    }
+  }
+}
+
+/// The raw object of the tweet behind every wrapper, resolved as [`TweetResult::tweet`] does.
+fn raw_tweet(raw: &Value) -> &Value {
+  let tweet = if raw["__typename"] == "TweetWithVisibilityResults" { &raw["tweet"] } else { raw };
+  match tweet["legacy"].get("retweeted_status_result") {
+    Some(retweeted) => raw_tweet(&retweeted["result"]),
+    None => tweet,
+  }
+}
+
+/// The raw object of the tweet quoted by the tweet in `raw`.
+fn quoted_raw(raw: &Value) -> &Value {
+  raw_tweet(&raw_tweet(raw)["quoted_status_result"]["result"])
+}
+
+/// Collects `tweet_results.result` of every entry and the bottom cursor from a timeline page.
+///
+/// Likes lists tweets as plain entries, while the media grid puts them in a module on the first
+/// page and in `moduleItems` of a `TimelineAddToModule` instruction later on.
+fn timeline_results(timeline: &Value) -> Option<(Vec<&Value>, String)> {
+  let mut results = Vec::new();
+  let mut entries = Vec::new();
+  for instruction in timeline["instructions"].as_array()? {
+    entries.extend(instruction["entries"].as_array().into_iter().flatten());
+    for item in instruction["moduleItems"].as_array().into_iter().flatten() {
+      results.push(&item["item"]["itemContent"]["tweet_results"]["result"]);
+    }
+  }
+  for entry in &entries {
+    results.push(&entry["content"]["itemContent"]["tweet_results"]["result"]);
+    for item in entry["content"]["items"].as_array().into_iter().flatten() {
+      results.push(&item["item"]["itemContent"]["tweet_results"]["result"]);
+    }
+  }
+  results.retain(|v| !v.is_null());
+
+  let cursor = entries.iter()
+    .find(|v| v["content"]["cursorType"] == "Bottom")
+    .or(entries.last())?["content"]["value"]
+    .as_str()?.to_owned();
+  Some((results, cursor))
+}
+
 #[derive(Deserialize)]
 #[allow(dead_code)]
 struct TwitterConfig {
   user_name: String,
-  authorization: String,
-  cookies: String,
-  csrf_token: String,
+  authorization: Option<String>,
+  cookies: Option<String>,
+  csrf_token: Option<String>,
+  timeline: Option<Timeline>,
   page_size: Option<i32>,
   concurrency: Option<usize>,
+  bandwidth: Option<BandwidthConfig>,
   path: Option<String>,
+  video_quality: Option<VideoQuality>,
+  hls: Option<bool>,
+  quoted: Option<bool>,
 }
 
 pub struct TwitterAdapter {
@@ -87,66 +191,134 @@ pub struct TwitterAdapter {
   pub path: String,
   userid: OnceLock<String>,
   cursor: Value,
-  xhr: Client,
-  file: Client,
+  timeline: Timeline,
+  /// `None` for a logged-in session, otherwise the current guest token once activated.
+  guest_token: Option<Mutex<Option<String>>>,
+  xhr: Http,
+  file: Http,
   cache: LinkedList<TwitterItem>,
   page_size: i32,
+  video_quality: VideoQuality,
+  hls: bool,
+  quoted: bool,
   sem: Arc<Semaphore>,
+  bandwidth: Arc<Bucket>,
+  scheduler: Arc<Scheduler>,
 }
 
 pub struct TwitterItem {
   pub url: String,
   pub media_url: String,
-  pub client: Client,
-  pub filename: String,
-  pub is_last: bool,
+  pub client: Http,
+  pub post: Arc<Post>,
+  pub index: usize,
+  pub ext: String,
+  pub kind: MediaKind,
+  /// Set when `media_url` is an HLS playlist, the segments of the chosen rendition are
+  /// fetched and joined into one file.
+  hls: Option<Hls>,
   sem: Arc<Semaphore>,
+  bandwidth: Arc<Bucket>,
+  scheduler: Arc<Scheduler>,
 }
 
 impl TwitterAdapter {
-  pub fn new(config: Value, proxy: Option<Proxy>) -> Self {
+  pub fn new(config: Value, http: &Http, scheduler: Arc<Scheduler>) -> Self {
     let mut headers = HeaderMap::new();
     let config: TwitterConfig = from_value(config).unwrap();
 
     insert!(
       headers,
-      "Authorization", config.authorization,
-      "X-Csrf-Token", config.csrf_token,
-      "Cookie", config.cookies
+      "Authorization", config.authorization.unwrap_or(GUEST_BEARER.to_owned())
     );
 
-    let mut builder = Client::builder()
-    .user_agent(USER_AGENT)
-    .default_headers(headers)
-    .gzip(true);
-
-    if let Some(proxy) = proxy.clone() {
-      builder = builder.proxy(proxy);
+    let guest = config.cookies.is_none() || config.csrf_token.is_none();
+    let timeline = config.timeline.unwrap_or_default();
+    if guest && timeline == Timeline::Likes {
+      panic!("Twitter account {} needs `cookies` and `csrf_token` for likes, or set `timeline` to \"media\".", config.user_name);
     }
-
-    let xhr = builder.build().unwrap();
-
-    builder = Client::builder().user_agent(USER_AGENT).gzip(true);
-    if let Some(proxy) = proxy.clone() {
-      builder = builder.proxy(proxy);
+    if let (Some(cookies), Some(csrf_token)) = (config.cookies, config.csrf_token) {
+      insert!(
+        headers,
+        "X-Csrf-Token", csrf_token,
+        "Cookie", cookies
+      );
     }
 
-    let file = builder.build().unwrap();
+    let xhr = http.with_headers(headers);
+    let file = http.clone();
 
     Self {
       username: config.user_name,
       page_size: config.page_size.unwrap_or(100),
+      video_quality: config.video_quality.unwrap_or_default(),
+      hls: config.hls.unwrap_or(false),
+      quoted: config.quoted.unwrap_or(false),
       path: config.path.unwrap_or("./twitter".to_owned()),
       cursor: Value::Null,
+      timeline,
+      guest_token: guest.then(|| Mutex::new(None)),
       userid: OnceLock::new(),
       cache: LinkedList::new(),
       sem: Arc::new(Semaphore::new(config.concurrency.unwrap_or(50))),
+      bandwidth: Arc::new(Bucket::new(config.bandwidth.as_ref())),
+      scheduler,
       xhr,
       file,
     }
   }
 
+  /// A GET request on the xhr client, carrying the guest token in guest mode.
+  fn api(&self, url: &str) -> RequestBuilder {
+    let req = self.xhr.get(url);
+    match self.guest_token.as_ref().and_then(|t| t.lock().unwrap().clone()) {
+      Some(token) => req.header("X-Guest-Token", token),
+      None => req,
+    }
+  }
+
+  /// Whether `err` is solved by a new guest token, which is then fetched.
+  async fn reactivate(&self, err: &reqwest::Error) -> bool {
+    let expired = matches!(
+      err.status(),
+      Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS)
+    );
+    if self.guest_token.is_none() || !expired {
+      return false;
+    }
+    message!("Warning: guest token rejected, activating a new one...");
+    self.activate().await;
+    true
+  }
+
+  async fn activate(&self) {
+    let Some(guest_token) = &self.guest_token else {
+      return;
+    };
+    loop {
+      match async {
+        self.xhr.post("https://api.twitter.com/1.1/guest/activate.json")
+          .send().await?.error_for_status()?.json::<Value>().await
+      }.await {
+        Ok(json) => {
+          *guest_token.lock().unwrap() = Some(json["guest_token"].as_str().unwrap().to_owned());
+          break;
+        }
+        Err(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
+          self.xhr.rate_limited(&err).await;
+        }
+        Err(err) if err.is_connect() => {}
+        Err(err) => {
+          message!("Unknown request error {:?}, retrying...", err);
+        }
+      }
+    }
+  }
+
   async fn init(&self) {
+    if self.guest_token.is_some() {
+      self.activate().await;
+    }
     let user_variables = json!({
       "screen_name": self.username,
       "withSafetyModeUserFields": true,
@@ -160,7 +332,7 @@ impl TwitterAdapter {
 
     loop {
       match async {
-        self.xhr.get("https://x.com/i/api/graphql/Yka-W8dz7RaEuQNkroPkYw/UserByScreenName")
+        self.api("https://x.com/i/api/graphql/Yka-W8dz7RaEuQNkroPkYw/UserByScreenName")
           .query(&query)
           .send().await?.error_for_status()?.json::<Value>().await
       }.await {
@@ -171,17 +343,105 @@ impl TwitterAdapter {
           self.userid.set(userid).unwrap();
           break;
         }
+        Err(err) if self.reactivate(&err).await => {}
         Err(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
-          println!("Warning: too many request, sleep 5 secs and retrying...");
-          sleep(FIVE_SECOUND).await;
+          self.xhr.rate_limited(&err).await;
         }
         Err(err) if err.is_connect() => {}
         Err(err) => {
-          println!("Unknown request error {:?}, retrying...", err);
+          message!("Unknown request error {:?}, retrying...", err);
         }
       }
     };
   }
+
+  /// Picks the URL to download a video, GIF or audio track from, and whether it is an HLS playlist.
+  fn video_source(&self, media: &Media) -> Option<(String, Option<Hls>)> {
+    let variants = media.video_info.as_ref().map(|v| v.variants.as_slice()).unwrap_or_default();
+    let (files, playlists): (Vec<_>, Vec<_>) = variants.iter()
+      .partition(|v| v.content_type != "application/x-mpegURL");
+    let file = self.video_quality.pick(files, |v| v.bitrate.unwrap_or(0));
+    match (file, playlists.first()) {
+      (Some(file), Some(playlist)) if self.hls => {
+        Some((playlist.url.clone(), Some(Hls { quality: self.video_quality, fallback: Some(file.url.clone()) })))
+      }
+      (Some(file), _) => Some((file.url.clone(), None)),
+      (None, Some(playlist)) => Some((playlist.url.clone(), Some(Hls { quality: self.video_quality, fallback: None }))),
+      (None, None) => None,
+    }
+  }
+
+  /// Queues the media of `tweet`, `raw` is the API object it was parsed from.
+  fn push_tweet(&mut self, tweet: &Tweet, raw: &Value) {
+    let (Some(username), Ok(snowflake)) = (tweet.screen_name(), tweet.rest_id.parse::<u64>()) else {
+      message!("Warning: tweet {} has no author, skipped.", tweet.rest_id);
+      return;
+    };
+
+    let mut sources = Vec::new();
+    for media in tweet.media() {
+      let kind = match media.kind.as_str() {
+        "photo" => MediaKind::Photo,
+        "video" => MediaKind::Video,
+        "animated_gif" => MediaKind::Gif,
+        "audio" => MediaKind::Audio,
+        media_type => MediaKind::Unknown(media_type.to_owned()),
+      };
+      let ext = Path::new(media.media_url_https.as_str()).extension()
+        .and_then(|v| v.to_str()).unwrap_or("bin").to_owned();
+      let alt_text = media.ext_alt_text.clone();
+      match kind {
+        MediaKind::Photo => sources.push((kind, media.media_url_https.clone() + "?name=orig", ext, None, alt_text)),
+        MediaKind::Video | MediaKind::Gif | MediaKind::Audio => match self.video_source(media) {
+          Some((url, hls)) => sources.push((kind, url, "mp4".to_owned(), hls, alt_text)),
+          None => message!("Warning: no playable variant in tweet {snowflake}, skipped."),
+        },
+        _ => sources.push((kind, media.media_url_https.clone(), ext, None, alt_text)),
+      }
+    }
+    if let Some(url) = tweet.card_image() {
+      let ext = url.split_once("format=")
+        .map(|(_, v)| v.split('&').next().unwrap_or_default())
+        .unwrap_or("jpg").to_owned();
+      sources.push((MediaKind::CardImage, url.to_owned(), ext, None, None));
+    }
+
+    let post = Arc::new(Post {
+      platform: "twitter",
+      author: username.to_owned(),
+      author_id: tweet.user_id().unwrap_or_default().to_owned(),
+      author_name: tweet.user_name().unwrap_or_default().to_owned(),
+      id: snowflake.to_string(),
+      url: format!("https://x.com/{username}/status/{snowflake}"),
+      created_at: tweet.created_at(),
+      text: tweet.text().to_owned(),
+      hashtags: tweet.hashtags(),
+      stats: tweet.stats(),
+      media: sources.iter().enumerate().map(|(index, (kind, url, _, _, alt_text))| PostMedia {
+        index: index + 1,
+        kind: kind.name(),
+        url: url.clone(),
+        alt_text: alt_text.clone(),
+      }).collect(),
+      raw: raw.clone(),
+    });
+    for (media_index, (kind, media_url, ext, hls, _)) in sources.into_iter().enumerate() {
+      let media_index = media_index + 1;
+      self.cache.push_back(TwitterItem {
+        client: self.file.clone(),
+        url: format!("http://x.com/{username}/status/{snowflake}/photo/{media_index}"),
+        media_url,
+        post: post.clone(),
+        index: media_index,
+        ext,
+        kind,
+        hls,
+        sem: self.sem.clone(),
+        bandwidth: self.bandwidth.clone(),
+        scheduler: self.scheduler.clone(),
+      });
+    }
+  }
 }
 
 impl Adapters for TwitterAdapter {
@@ -218,22 +478,22 @@ impl Adapters for TwitterAdapter {
       while retry_count < 5 {
         json = loop {
           match async {
-            self.xhr.get("https://api.twitter.com/graphql/QK8AVO3RpcnbLPKXLAiVog/Likes")
+            self.api(self.timeline.endpoint())
               .query(&query)
               .send().await?.error_for_status()?.json::<Value>().await
           }.await {
             Ok(json) if json.get("error").is_some() => {
-              println!("Warning: server interal error, sleep 5 secs and retrying...");
+              message!("Warning: server interal error, sleep 5 secs and retrying...");
               sleep(FIVE_SECOUND).await;
             },
             Ok(json) => break json,
+            Err(err) if self.reactivate(&err).await => {}
             Err(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
-              println!("Warning: too many request, sleep 5 secs and retrying...");
-              sleep(FIVE_SECOUND).await;
+              self.xhr.rate_limited(&err).await;
             }
             Err(err) if err.is_connect() => {}
             Err(err) => {
-              println!("Unknown request error {:?}, retrying...", err);
+              message!("Unknown request error {:?}, retrying...", err);
             }
           }
         };
@@ -242,60 +502,21 @@ impl Adapters for TwitterAdapter {
           let timeline = json["data"]["user"]["result"].get("timeline")
             .or(json["data"]["user"]["result"].get("timeline_v2"))?;
         
-          let likes = timeline["timeline"]["instructions"][0]["entries"].as_array()?;
-          let new_cursor = likes.last()?["content"]["value"].as_str()?.to_owned();
-          for item in likes {
-            let result = &item["content"]["itemContent"]["tweet_results"]["result"];
-            if result.is_null() {
+          let (results, new_cursor) = timeline_results(&timeline["timeline"])?;
+          for raw in results {
+            let Ok(result) = TweetResult::deserialize(raw) else {
+              message!("Warning: malform tweet in timeline, skipped.");
               continue;
-            }
-          
-            let snowflake = result["legacy"]["id_str"].as_str()
-              .or(result["tweet"]["legacy"]["id_str"].as_str())
-              ?.to_owned().parse::<u64>().ok()?;
-          
-            let username = result["core"]["user_results"]["result"]["legacy"]["screen_name"].as_str()
-              .or(result["tweet"]["core"]["user_results"]["result"]["legacy"]["screen_name"].as_str())
-              ?.to_owned();
-          
-            let temp = &result["legacy"]["entities"]["media"];
-            let media = temp.as_array();
-          
-            if let Some(media) = media {
-              for (media_index, item) in media.iter().enumerate() {
-                let media_index = media_index + 1;
-                let media_type = item["type"].as_str()?;
-                let (media_url, ext) = match media_type {
-                  "photo" => {
-                    let media_url_https = item["media_url_https"].as_str()?.to_owned();
-                    let url = media_url_https.clone() + "?name=orig";
-                    let ext = Path::new(media_url_https.as_str()).extension().unwrap().to_str().unwrap().to_owned();
-                    (url, ext)
-                  }
-                  "animated_gif" | "video" => {
-                    let media_url_https = item["video_info"]["variants"]
-                      .as_array()?
-                      .last()?["url"]
-                      .as_str()?.to_owned();
-                    (media_url_https, "mp4".to_owned())
-                  }
-                  _ => panic!("Unknown media type {}.", media_type)
-                };
-                let filename = format!("{username} {snowflake} {media_index}.{ext}");
-                self.cache.push_back(TwitterItem {
-                  client: self.file.clone(),
-                  url: format!("http://x.com/{username}/status/{snowflake}/photo/{media_index}"),
-                  media_url,
-                  filename,
-                  is_last: false,
-                  sem: self.sem.clone(),
-                });
-              }
-              if let Some(item) = self.cache.back_mut() {
-                item.is_last = true
-              }
+            };
+            let Some(tweet) = result.tweet() else {
+              continue;
+            };
+            self.push_tweet(tweet, raw);
+            if let Some(quoted) = tweet.quoted().filter(|_| self.quoted) {
+              self.push_tweet(quoted, quoted_raw(raw));
             }
           }
+          emit(Event::PageFetched { platform: self.platform(), account: &self.username, items: self.cache.len() });
         
           if new_cursor == self.cursor {
             return None;
@@ -307,11 +528,11 @@ impl Adapters for TwitterAdapter {
         };
         if is_error.is_none() {
           let mut file = tokio::fs::File::create("./twitter_sample.json").await.unwrap();
-          file.write(to_string_pretty(&json).unwrap().as_bytes()).await.unwrap();
-          println!("Warning: malform json, sleep 5 secs and retrying...");
+          file.write_all(to_string_pretty(&json).unwrap().as_bytes()).await.unwrap();
+          message!("Warning: malform json, sleep 5 secs and retrying...");
           sleep(FIVE_SECOUND).await;
         } else {
-          println!("Undefined behavior， sleep 5 secs and retrying...");
+          message!("Undefined behavior， sleep 5 secs and retrying...");
           sleep(FIVE_SECOUND).await;
         }
         retry_count += 1;
@@ -319,7 +540,7 @@ impl Adapters for TwitterAdapter {
 
       if is_error.is_none() {
         let mut file = tokio::fs::File::create("./twitter_sample.json").await.unwrap();
-        file.write(to_string_pretty(&json).unwrap().as_bytes()).await.unwrap();
+        file.write_all(to_string_pretty(&json).unwrap().as_bytes()).await.unwrap();
         panic!("Error: malform json");
       } else {
         panic!("Undefined behavior.");
@@ -327,11 +548,72 @@ impl Adapters for TwitterAdapter {
     };
     Box::pin(futures)
   }
+
+  fn post(&mut self, id: &str) -> BoxedFuture<'_, Vec<Box<dyn Item>>> {
+    Box::pin(self.fetch_tweet(id.to_owned()))
+  }
+}
+
+impl TwitterAdapter {
+  async fn fetch_tweet(&mut self, id: String) -> Vec<Box<dyn Item>> {
+    if self.guest_token.as_ref().is_some_and(|t| t.lock().unwrap().is_none()) {
+      self.activate().await;
+    }
+    let variables = json!({
+      "tweetId": id,
+      "withCommunity": false,
+      "includePromotedContent": false,
+      "withVoice": false,
+    }).to_string();
+    let query = [
+      ("variables", variables.as_str()),
+      ("features", TWEET_FEATURE.as_str()),
+    ];
+
+    let json = loop {
+      match async {
+        self.api("https://x.com/i/api/graphql/2ICDjqPd81tulZcYrtpTuQ/TweetResultByRestId")
+          .query(&query)
+          .send().await?.error_for_status()?.json::<Value>().await
+      }.await {
+        Ok(json) => break json,
+        Err(err) if self.reactivate(&err).await => {}
+        Err(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
+          self.xhr.rate_limited(&err).await;
+        }
+        Err(err) if err.is_connect() => {}
+        Err(err) => {
+          message!("Unknown request error {:?}, retrying...", err);
+        }
+      }
+    };
+
+    let result = TweetResult::deserialize(&json["data"]["tweetResult"]["result"]);
+    let Some(tweet) = result.as_ref().ok().and_then(TweetResult::tweet) else {
+      message!("Warning: tweet {id} is unavailable, skipped.");
+      return Vec::new();
+    };
+    let len = self.cache.len();
+    let raw = &json["data"]["tweetResult"]["result"];
+    self.push_tweet(tweet, raw);
+    if let Some(quoted) = tweet.quoted().filter(|_| self.quoted) {
+      self.push_tweet(quoted, quoted_raw(raw));
+    }
+    self.cache.split_off(len).into_iter().map(|v| Box::new(v) as Box<dyn Item>).collect()
+  }
 }
 
 impl Item for TwitterItem {
-  fn filename(&self) -> &str {
-    &self.filename
+  fn post(&self) -> &Post {
+    &self.post
+  }
+
+  fn index(&self) -> usize {
+    self.index
+  }
+
+  fn ext(&self) -> &str {
+    &self.ext
   }
 
   fn media_url(&self) -> &str {
@@ -342,50 +624,75 @@ impl Item for TwitterItem {
     &self.url
   }
 
+  fn kind(&self) -> &MediaKind {
+    &self.kind
+  }
+
+  fn alt_text(&self) -> Option<&str> {
+    self.post.media.get(self.index - 1).and_then(|m| m.alt_text.as_deref())
+  }
+
+  fn post_text(&self) -> &str {
+    &self.post.text
+  }
+
+  fn timestamp(&self) -> Option<DateTime<Utc>> {
+    self.post.created_at
+  }
+
   fn get(&self) -> BoxedFuture<'_, Bytes> {
     Box::pin(async {
-      let _guard = self.sem.acquire().await.unwrap();
-      let mut bytes = BytesMut::new();
-      let content_length = OnceCell::<u64>::new();
-      while {
-        let mut req = self.client.get(&self.media_url);
-        if bytes.len() > 0 {
-          req = req.header("Range", bytes.len());
+      let permit = self.scheduler.acquire(&self.sem, &self.bandwidth, &self.media_url).await;
+      if let Some(Hls { quality, fallback }) = &self.hls {
+        if let Some(bytes) = hls::download(&self.client, &self.media_url, *quality, &permit).await {
+          return bytes;
         }
-        match req.send().await.and_then(|r| r.error_for_status()){
-          Ok(res) => {
-            let _ = content_length.set(res.content_length().unwrap_or(0));
-            match res.bytes().await {
-              Ok(b) => bytes.extend(b),
-              Err(err) => println!("IO error {:?}, retrying...", err),
-            };
-          },
-          Err(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
-            println!("Warning: too many request, sleep 5 secs and retrying...");
-            sleep(FIVE_SECOUND).await;
-          }
-          Err(err) if err.is_connect() => {}
-          Err(err) => {
-            println!("Unknown request error {:?}, retrying...", err);
-          }
+        let Some(fallback) = fallback else {
+          message!("Warning: cannot read HLS stream {}, skipped.", self.media_url);
+          return Bytes::new();
         };
-        let is_complete = content_length.get().map(|v| bytes.len() as u64 >= *v).unwrap_or(false);
-        !is_complete
-      } {}
-
-      return bytes.freeze();
-      // loop {
-      //   match self.client.get(&self.media_url).send().await.and_then(|r| r.error_for_status()){
-      //     Ok(res) => return res.bytes().await.unwrap(),
-      //     Err(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
-      //       println!("Warning: too many request, sleep 5 secs and retrying...");
-      //       sleep(FIVE_SECOUND).await;
-      //     }
-      //     Err(err) => {
-      //       println!("Unknown request error {:?}, retrying...", err);
-      //     }
-      //   }
-      // };
+        message!("Warning: cannot read HLS stream {}, downloading the mp4 variant instead.", self.media_url);
+        return self.client.download(fallback, &permit).await;
+      }
+      self.client.download(&self.media_url, &permit).await
     })
   }
-}
\ No newline at end of file
+
+  fn size(&self) -> BoxedFuture<'_, Option<u64>> {
+    Box::pin(async {
+      if self.hls.is_some() {
+        return None;
+      }
+      let _permit = self.scheduler.acquire(&self.sem, &self.bandwidth, &self.media_url).await;
+      self.client.size(&self.media_url).await
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn quoted_raw_unwraps_retweets_and_visibility() {
+    let raw = json!({
+      "__typename": "Tweet",
+      "rest_id": "1",
+      "legacy": { "retweeted_status_result": { "result": {
+        "__typename": "TweetWithVisibilityResults",
+        "tweet": {
+          "rest_id": "2",
+          "legacy": {},
+          "quoted_status_result": { "result": {
+            "__typename": "TweetWithVisibilityResults",
+            "tweet": { "rest_id": "3", "legacy": {} },
+          } },
+        },
+      } } },
+    });
+    assert_eq!(raw_tweet(&raw)["rest_id"], "2");
+    assert_eq!(quoted_raw(&raw)["rest_id"], "3");
+    let result = TweetResult::deserialize(&raw).unwrap();
+    assert_eq!(result.tweet().and_then(Tweet::quoted).map(|v| v.rest_id.as_str()), Some("3"));
+  }
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+
+use bytes::{Bytes, BytesMut};
+use reqwest::{StatusCode, Url};
+
+use super::VideoQuality;
+use crate::adapters::http::Http;
+use crate::adapters::scheduler::Permit;
+use crate::embed::{boxes, mp4_box, Mp4Box};
+
+/// How to download a video from its HLS playlist.
+pub struct Hls {
+  pub quality: VideoQuality,
+  /// The mp4 variant downloaded instead when the playlist cannot be read.
+  pub fallback: Option<String>,
+}
+
+/// Splits an attribute list like `BANDWIDTH=256000,CODECS="avc1,mp4a"` into key-value pairs.
+fn attributes(list: &str) -> Vec<(&str, &str)> {
+  let mut attributes = Vec::new();
+  let mut rest = list;
+  while let Some((key, value)) = rest.split_once('=') {
+    let (value, next) = if let Some(quoted) = value.strip_prefix('"') {
+      let end = quoted.find('"').unwrap_or(quoted.len());
+      (&quoted[..end], quoted[end..].trim_start_matches('"'))
+    } else {
+      value.split_once(',').unwrap_or((value, ""))
+    };
+    attributes.push((key.trim(), value));
+    rest = next.trim_start_matches(',');
+  }
+  attributes
+}
+
+fn attribute<'a>(line: &'a str, tag: &str, key: &str) -> Option<&'a str> {
+  attributes(line.strip_prefix(tag)?).into_iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+}
+
+/// Fetches `url`, `None` when the server rejects it for good.
+async fn fetch(client: &Http, url: &Url, permit: &Permit<'_>) -> Option<Bytes> {
+  loop {
+    let mut bytes = BytesMut::new();
+    match async {
+      permit.read(client.get(url.clone()).send().await?.error_for_status()?, &mut bytes).await
+    }.await {
+      Ok(()) => return Some(bytes.freeze()),
+      Err(err) if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
+        client.rate_limited(&err).await;
+      }
+      Err(err) if err.status().is_some_and(|v| v.is_client_error()) => {
+        message!("Warning: {} answered {}.", url, err.status().unwrap());
+        return None;
+      }
+      Err(err) if err.is_connect() => {}
+      Err(err) if err.is_timeout() => message!("Warning: request to {} timed out, retrying...", url),
+      Err(err) => {
+        message!("Unknown request error {:?}, retrying...", err);
+      }
+    }
+  }
+}
+
+async fn playlist(client: &Http, url: &Url, permit: &Permit<'_>) -> Option<String> {
+  Some(String::from_utf8_lossy(&fetch(client, url, permit).await?).into_owned())
+}
+
+/// The init section, if any, followed by every segment of a media playlist.
+async fn segments(client: &Http, url: &Url, permit: &Permit<'_>) -> Option<Vec<Bytes>> {
+  let mut segments = Vec::new();
+  for line in playlist(client, url, permit).await?.lines().map(str::trim) {
+    let uri = match attribute(line, "#EXT-X-MAP:", "URI") {
+      Some(uri) => uri,
+      None if !line.is_empty() && !line.starts_with('#') => line,
+      None => continue,
+    };
+    segments.push(fetch(client, &url.join(uri).ok()?, permit).await?);
+  }
+  (!segments.is_empty()).then_some(segments)
+}
+
+/// Downloads an HLS stream into a single MP4 file, `None` when the stream cannot be read.
+///
+/// When `url` is a master playlist the rendition is chosen by `BANDWIDTH` following `quality`.
+/// Twitter serves fragmented MP4 with the audio as a separate rendition, which is added to the
+/// video as a second track.
+pub async fn download(client: &Http, url: &str, quality: VideoQuality, permit: &Permit<'_>) -> Option<Bytes> {
+  let url = Url::parse(url).ok()?;
+  let master = playlist(client, &url, permit).await?;
+  if !master.contains("#EXT-X-STREAM-INF") {
+    return Some(segments(client, &url, permit).await?.concat().into());
+  }
+
+  let mut streams = Vec::new();
+  let mut audio = HashMap::new();
+  let mut lines = master.lines();
+  while let Some(line) = lines.next() {
+    if let Some(bandwidth) = attribute(line, "#EXT-X-STREAM-INF:", "BANDWIDTH") {
+      let bandwidth = bandwidth.parse::<u64>().unwrap_or(0);
+      let group = attribute(line, "#EXT-X-STREAM-INF:", "AUDIO");
+      if let Some(uri) = lines.by_ref().map(str::trim).find(|l| !l.is_empty() && !l.starts_with('#')) {
+        streams.push((bandwidth, uri, group));
+      }
+    } else if attribute(line, "#EXT-X-MEDIA:", "TYPE") == Some("AUDIO") {
+      if let (Some(group), Some(uri)) = (attribute(line, "#EXT-X-MEDIA:", "GROUP-ID"), attribute(line, "#EXT-X-MEDIA:", "URI")) {
+        audio.entry(group).or_insert(uri);
+      }
+    }
+  }
+  let (_, uri, group) = quality.pick(streams, |(bandwidth, _, _)| *bandwidth)?;
+  let video = segments(client, &url.join(uri).ok()?, permit).await?;
+  let Some(audio) = group.and_then(|group| audio.get(group)) else {
+    return Some(video.concat().into());
+  };
+  let audio = segments(client, &url.join(audio).ok()?, permit).await?;
+  mux(&video, &audio).map(BytesMut::freeze)
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+  Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn write_u32(data: &mut [u8], at: usize, value: u32) -> Option<()> {
+  data.get_mut(at..at + 4)?.copy_from_slice(&value.to_be_bytes());
+  Some(())
+}
+
+/// Where the `track_ID` of the `tkhd` in `trak` is, by the version of `tkhd`.
+fn track_id_at(data: &[u8], trak: &Mp4Box) -> Option<usize> {
+  let tkhd = boxes(data, trak.body, trak.end)?.into_iter().find(|b| &b.kind == b"tkhd")?;
+  Some(tkhd.body + if *data.get(tkhd.body)? == 1 { 20 } else { 12 })
+}
+
+/// Joins a fragmented MP4 video and audio, each an init section followed by segments, into one
+/// file with the audio as a new track. Fragments alternate segment by segment.
+fn mux(video: &[Bytes], audio: &[Bytes]) -> Option<BytesMut> {
+  let (video_init, video_segments) = video.split_first()?;
+  let (audio_init, audio_segments) = audio.split_first()?;
+
+  let audio_moov = boxes(audio_init, 0, audio_init.len())?.into_iter().find(|b| &b.kind == b"moov")?;
+  let audio_children = boxes(audio_init, audio_moov.body, audio_moov.end)?;
+  let audio_trak = audio_children.iter().find(|b| &b.kind == b"trak")?;
+  let audio_mvex = audio_children.iter().find(|b| &b.kind == b"mvex")?;
+  let audio_trex = boxes(audio_init, audio_mvex.body, audio_mvex.end)?.into_iter().find(|b| &b.kind == b"trex")?;
+
+  let top = boxes(video_init, 0, video_init.len())?;
+  let moov = top.iter().find(|b| &b.kind == b"moov")?;
+  let children = boxes(video_init, moov.body, moov.end)?;
+  let mut id = 0;
+  for trak in children.iter().filter(|b| &b.kind == b"trak") {
+    id = id.max(read_u32(video_init, track_id_at(video_init, trak)?)?);
+  }
+  let id = id.checked_add(1)?;
+
+  let mut trak = audio_init[audio_trak.start..audio_trak.end].to_vec();
+  let at = track_id_at(audio_init, audio_trak)? - audio_trak.start;
+  write_u32(&mut trak, at, id)?;
+  let mut trex = audio_init[audio_trex.start..audio_trex.end].to_vec();
+  write_u32(&mut trex, audio_trex.body - audio_trex.start + 4, id)?;
+
+  let mut content = Vec::new();
+  let mut after_traks = None;
+  let mut has_mvex = false;
+  for child in &children {
+    let bytes = &video_init[child.start..child.end];
+    match &child.kind {
+      b"mvhd" => {
+        let mut mvhd = bytes.to_vec();
+        let len = mvhd.len();
+        write_u32(&mut mvhd, len.checked_sub(4)?, id + 1)?;
+        content.extend(mvhd);
+      }
+      b"mvex" => {
+        has_mvex = true;
+        let mut mvex = video_init[child.body..child.end].to_vec();
+        mvex.extend(&trex);
+        content.extend(mp4_box(b"mvex", &mvex));
+      }
+      _ => content.extend(bytes),
+    }
+    if &child.kind == b"trak" {
+      after_traks = Some(content.len());
+    }
+  }
+  if !has_mvex {
+    return None;
+  }
+  let at = after_traks?;
+  content.splice(at..at, trak);
+
+  let mut out = BytesMut::new();
+  for b in &top {
+    if &b.kind == b"moov" {
+      out.extend(mp4_box(b"moov", &content));
+    } else {
+      out.extend_from_slice(&video_init[b.start..b.end]);
+    }
+  }
+  let mut sequence = 0;
+  for i in 0..video_segments.len().max(audio_segments.len()) {
+    if let Some(segment) = video_segments.get(i) {
+      fragments(&mut out, segment, None, &mut sequence)?;
+    }
+    if let Some(segment) = audio_segments.get(i) {
+      fragments(&mut out, segment, Some(id), &mut sequence)?;
+    }
+  }
+  Some(out)
+}
+
+/// Copies the `moof` and `mdat` boxes of `segment` to `out`, numbering fragments by `sequence`
+/// and moving them to `track` when set. Data offsets must be relative to each `moof`.
+fn fragments(out: &mut BytesMut, segment: &[u8], track: Option<u32>, sequence: &mut u32) -> Option<()> {
+  for b in boxes(segment, 0, segment.len())? {
+    match &b.kind {
+      b"moof" => {
+        let mut moof = segment[b.start..b.end].to_vec();
+        let body = b.body - b.start;
+        let len = moof.len();
+        for child in boxes(&moof, body, len)? {
+          match &child.kind {
+            b"mfhd" => {
+              *sequence += 1;
+              write_u32(&mut moof, child.body + 4, *sequence)?;
+            }
+            b"traf" => {
+              for tfhd in boxes(&moof, child.body, child.end)?.into_iter().filter(|b| &b.kind == b"tfhd") {
+                // base-data-offset-present, offsets from the start of the file.
+                if moof.get(tfhd.body + 3)? & 1 == 1 {
+                  return None;
+                }
+                if let Some(track) = track {
+                  write_u32(&mut moof, tfhd.body + 4, track)?;
+                }
+              }
+            }
+            _ => {}
+          }
+        }
+        out.extend_from_slice(&moof);
+      }
+      b"mdat" => out.extend_from_slice(&segment[b.start..b.end]),
+      _ => {}
+    }
+  }
+  Some(())
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::BufMut;
+
+  use super::*;
+
+  fn full_box(kind: &[u8; 4], content: &[u8]) -> BytesMut {
+    let mut body = vec![0; 4];
+    body.extend_from_slice(content);
+    mp4_box(kind, &body)
+  }
+
+  fn init(track: u32) -> Bytes {
+    let mut tkhd = vec![0; 8];
+    tkhd.extend_from_slice(&track.to_be_bytes());
+    tkhd.extend_from_slice(&[0; 68]);
+    let mut mvhd = vec![0; 92];
+    mvhd.extend_from_slice(&(track + 1).to_be_bytes());
+    let mut trex = track.to_be_bytes().to_vec();
+    trex.extend_from_slice(&[0; 16]);
+
+    let mut moov = full_box(b"mvhd", &mvhd);
+    moov.extend(mp4_box(b"trak", &full_box(b"tkhd", &tkhd)));
+    moov.extend(mp4_box(b"mvex", &full_box(b"trex", &trex)));
+    let mut out = mp4_box(b"ftyp", b"iso6\0\0\0\0iso6");
+    out.extend(mp4_box(b"moov", &moov));
+    out.freeze()
+  }
+
+  fn segment(track: u32, sequence: u32, data: u8) -> Bytes {
+    let mut tfhd = BytesMut::new();
+    tfhd.put_u32(0x020000);
+    tfhd.put_u32(track);
+    let mut moof = full_box(b"mfhd", &sequence.to_be_bytes());
+    moof.extend(mp4_box(b"traf", &mp4_box(b"tfhd", &tfhd)));
+    let mut out = mp4_box(b"styp", b"msdh\0\0\0\0");
+    out.extend(mp4_box(b"moof", &moof));
+    out.extend(mp4_box(b"mdat", &[data; 8]));
+    out.freeze()
+  }
+
+  /// Every box at `path`, descending into the first match of each parent.
+  fn find(data: &[u8], path: &[&[u8; 4]]) -> Vec<Mp4Box> {
+    let (mut start, mut end) = (0, data.len());
+    let mut found = Vec::new();
+    for kind in path {
+      found = boxes(data, start, end).unwrap().into_iter().filter(|b| &b.kind == *kind).collect();
+      (start, end) = (found[0].body, found[0].end);
+    }
+    found
+  }
+
+  #[test]
+  fn attribute_lists() {
+    let line = r#"#EXT-X-STREAM-INF:BANDWIDTH=256000,CODECS="avc1,mp4a",AUDIO="audio-64000""#;
+    assert_eq!(attribute(line, "#EXT-X-STREAM-INF:", "BANDWIDTH"), Some("256000"));
+    assert_eq!(attribute(line, "#EXT-X-STREAM-INF:", "CODECS"), Some("avc1,mp4a"));
+    assert_eq!(attribute(line, "#EXT-X-STREAM-INF:", "AUDIO"), Some("audio-64000"));
+    assert_eq!(attribute(line, "#EXT-X-MEDIA:", "AUDIO"), None);
+  }
+
+  #[test]
+  fn mux_adds_audio_track() {
+    let video = [init(1), segment(1, 1, 0xaa), segment(1, 2, 0xab)];
+    let audio = [init(1), segment(1, 1, 0xba), segment(1, 2, 0xbb)];
+    let out = mux(&video, &audio).unwrap();
+
+    let ids: Vec<_> = find(&out, &[b"moov", b"trak"]).iter()
+      .map(|trak| read_u32(&out, track_id_at(&out, trak).unwrap()).unwrap()).collect();
+    assert_eq!(ids, [1, 2]);
+    let trexes: Vec<_> = find(&out, &[b"moov", b"mvex", b"trex"]).iter()
+      .map(|b| read_u32(&out, b.body + 4).unwrap()).collect();
+    assert_eq!(trexes, [1, 2]);
+    let mvhd = &find(&out, &[b"moov", b"mvhd"])[0];
+    assert_eq!(read_u32(&out, mvhd.end - 4), Some(3));
+
+    let top = boxes(&out, 0, out.len()).unwrap();
+    assert!(!top.iter().any(|b| &b.kind == b"styp"));
+    let moofs: Vec<_> = top.iter().filter(|b| &b.kind == b"moof").collect();
+    let fragments: Vec<_> = moofs.iter().map(|moof| {
+      let children = boxes(&out, moof.body, moof.end).unwrap();
+      let sequence = read_u32(&out, children[0].body + 4).unwrap();
+      let tfhd = &boxes(&out, children[1].body, children[1].end).unwrap()[0];
+      (sequence, read_u32(&out, tfhd.body + 4).unwrap(), out[moof.end + 8])
+    }).collect();
+    assert_eq!(fragments, [(1, 1, 0xaa), (2, 2, 0xba), (3, 1, 0xab), (4, 2, 0xbb)]);
+  }
+
+  #[test]
+  fn mux_rejects_absolute_offsets() {
+    let mut audio = segment(1, 1, 0).to_vec();
+    let at = audio.windows(4).position(|v| v == b"tfhd").unwrap() + 7;
+    audio[at] |= 1;
+    assert!(mux(&[init(1), segment(1, 1, 0)], &[init(1), audio.into()]).is_none());
+  }
+}
@@ -0,0 +1,214 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::adapters::Stats;
+
+/// `tweet_results.result` in timeline entries, tagged by `__typename`.
+#[derive(Deserialize)]
+#[serde(tag = "__typename")]
+pub enum TweetResult {
+  Tweet(Tweet),
+  TweetWithVisibilityResults { tweet: Tweet },
+  #[serde(other)]
+  Unavailable,
+}
+
+/// Wrapper around nested results such as `quoted_status_result` and `retweeted_status_result`.
+#[derive(Deserialize)]
+pub struct Results {
+  pub result: Option<Box<TweetResult>>,
+}
+
+#[derive(Deserialize)]
+pub struct Tweet {
+  pub rest_id: String,
+  pub core: Option<Core>,
+  pub legacy: Option<Legacy>,
+  pub quoted_status_result: Option<Results>,
+  pub card: Option<Card>,
+}
+
+#[derive(Deserialize)]
+pub struct Core {
+  pub user_results: UserResults,
+}
+
+#[derive(Deserialize)]
+pub struct UserResults {
+  pub result: User,
+}
+
+#[derive(Deserialize)]
+pub struct User {
+  pub rest_id: Option<String>,
+  pub legacy: Option<UserNames>,
+  pub core: Option<UserNames>,
+}
+
+#[derive(Deserialize)]
+pub struct UserNames {
+  pub screen_name: Option<String>,
+  pub name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Legacy {
+  pub created_at: Option<String>,
+  pub full_text: Option<String>,
+  pub favorite_count: Option<u64>,
+  pub retweet_count: Option<u64>,
+  pub reply_count: Option<u64>,
+  pub quote_count: Option<u64>,
+  #[serde(default)]
+  pub entities: Entities,
+  pub extended_entities: Option<Entities>,
+  pub retweeted_status_result: Option<Results>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct Entities {
+  #[serde(default)]
+  pub media: Vec<Media>,
+  #[serde(default)]
+  pub hashtags: Vec<Hashtag>,
+}
+
+#[derive(Deserialize)]
+pub struct Hashtag {
+  pub text: String,
+}
+
+#[derive(Deserialize)]
+pub struct Media {
+  #[serde(rename = "type")]
+  pub kind: String,
+  pub media_url_https: String,
+  pub video_info: Option<VideoInfo>,
+  pub ext_alt_text: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct VideoInfo {
+  #[serde(default)]
+  pub variants: Vec<Variant>,
+}
+
+#[derive(Deserialize)]
+pub struct Variant {
+  pub content_type: String,
+  pub url: String,
+  pub bitrate: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct Card {
+  pub legacy: CardLegacy,
+}
+
+#[derive(Deserialize)]
+pub struct CardLegacy {
+  #[serde(default)]
+  pub binding_values: Vec<BindingValue>,
+}
+
+#[derive(Deserialize)]
+pub struct BindingValue {
+  pub key: String,
+  pub value: BindingValueData,
+}
+
+#[derive(Deserialize)]
+pub struct BindingValueData {
+  pub image_value: Option<ImageValue>,
+}
+
+#[derive(Deserialize)]
+pub struct ImageValue {
+  pub url: String,
+}
+
+impl TweetResult {
+  /// The tweet behind every wrapper, retweets resolve to the original tweet.
+  pub fn tweet(&self) -> Option<&Tweet> {
+    let tweet = match self {
+      TweetResult::Tweet(tweet) => tweet,
+      TweetResult::TweetWithVisibilityResults { tweet } => tweet,
+      TweetResult::Unavailable => return None,
+    };
+    match tweet.legacy.as_ref().and_then(|l| l.retweeted_status_result.as_ref()) {
+      Some(retweeted) => retweeted.result.as_ref()?.tweet(),
+      None => Some(tweet),
+    }
+  }
+}
+
+impl Tweet {
+  pub fn screen_name(&self) -> Option<&str> {
+    let user = &self.core.as_ref()?.user_results.result;
+    user.core.as_ref().and_then(|c| c.screen_name.as_deref())
+      .or(user.legacy.as_ref().and_then(|l| l.screen_name.as_deref()))
+  }
+
+  pub fn user_name(&self) -> Option<&str> {
+    let user = &self.core.as_ref()?.user_results.result;
+    user.core.as_ref().and_then(|c| c.name.as_deref())
+      .or(user.legacy.as_ref().and_then(|l| l.name.as_deref()))
+  }
+
+  pub fn user_id(&self) -> Option<&str> {
+    self.core.as_ref()?.user_results.result.rest_id.as_deref()
+  }
+
+  /// `created_at` looks like `Wed Oct 10 20:19:24 +0000 2018`.
+  pub fn created_at(&self) -> Option<DateTime<Utc>> {
+    let created_at = self.legacy.as_ref()?.created_at.as_deref()?;
+    DateTime::parse_from_str(created_at, "%a %b %d %H:%M:%S %z %Y").ok().map(|v| v.to_utc())
+  }
+
+  pub fn text(&self) -> &str {
+    self.legacy.as_ref().and_then(|l| l.full_text.as_deref()).unwrap_or_default()
+  }
+
+  pub fn hashtags(&self) -> Vec<String> {
+    let Some(legacy) = &self.legacy else {
+      return Vec::new();
+    };
+    legacy.entities.hashtags.iter().map(|v| v.text.clone()).collect()
+  }
+
+  pub fn stats(&self) -> Stats {
+    let Some(legacy) = &self.legacy else {
+      return Stats::default();
+    };
+    Stats {
+      likes: legacy.favorite_count,
+      reposts: legacy.retweet_count,
+      replies: legacy.reply_count,
+      quotes: legacy.quote_count,
+    }
+  }
+
+  /// `extended_entities` lists every photo of a multi-photo post, `entities` only the first.
+  pub fn media(&self) -> &[Media] {
+    let Some(legacy) = &self.legacy else {
+      return &[];
+    };
+    match &legacy.extended_entities {
+      Some(extended) if !extended.media.is_empty() => &extended.media,
+      _ => &legacy.entities.media,
+    }
+  }
+
+  /// The largest preview image of a link card.
+  pub fn card_image(&self) -> Option<&str> {
+    let values = &self.card.as_ref()?.legacy.binding_values;
+    ["photo_image_full_size_original", "summary_photo_image_original", "thumbnail_image_original"]
+      .iter()
+      .find_map(|key| values.iter().find(|v| v.key == *key)?.value.image_value.as_ref())
+      .map(|image| image.url.as_str())
+  }
+
+  pub fn quoted(&self) -> Option<&Tweet> {
+    self.quoted_status_result.as_ref()?.result.as_ref()?.tweet()
+  }
+}
@@ -0,0 +1,59 @@
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use tokio::process::Command;
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum GifFormat {
+  Gif,
+  Webp,
+}
+
+impl GifFormat {
+  pub fn ext(self) -> &'static str {
+    match self {
+      GifFormat::Gif => "gif",
+      GifFormat::Webp => "webp",
+    }
+  }
+}
+
+/// Turns Twitter's `animated_gif` mp4 into an animated image after download.
+#[derive(Deserialize)]
+pub struct ConvertGif {
+  pub format: GifFormat,
+  /// Keeps the downloaded mp4 next to the converted file, default is true.
+  pub keep_original: Option<bool>,
+}
+
+impl ConvertGif {
+  pub fn keep_original(&self) -> bool {
+    self.keep_original.unwrap_or(true)
+  }
+
+  /// The filename the converted file of `filename` is written to.
+  pub fn target(&self, filename: &str) -> String {
+    Path::new(filename).with_extension(self.format.ext()).to_string_lossy().into_owned()
+  }
+
+  /// Converts `source` with ffmpeg, returning the path of the new file.
+  ///
+  /// Decoding H.264 has no pure Rust implementation, so an ffmpeg binary is required,
+  /// `ffmpeg` is looked up in `PATH` when no path is configured.
+  pub async fn convert(&self, ffmpeg: &str, source: &Path) -> std::io::Result<PathBuf> {
+    let target = source.with_extension(self.format.ext());
+    let mut command = Command::new(ffmpeg);
+    command.args(["-y", "-loglevel", "error", "-i"]).arg(source);
+    match self.format {
+      GifFormat::Gif => command.args(["-vf", "split[a][b];[a]palettegen[p];[b][p]paletteuse", "-loop", "0"]),
+      GifFormat::Webp => command.args(["-c:v", "libwebp", "-q:v", "80", "-loop", "0", "-an"]),
+    };
+    let output = command.arg(&target).output().await?;
+    if !output.status.success() {
+      return Err(Error::other(String::from_utf8_lossy(&output.stderr).into_owned()));
+    }
+    Ok(target)
+  }
+}
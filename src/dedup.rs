@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{absolute, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
+
+pub const STORE: &str = "./.content.jsonl";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+  #[default]
+  Hardlink,
+  /// Copy on write clone, a plain copy where the filesystem cannot do it.
+  Reflink,
+  Symlink,
+  /// Saves nothing, the manifest points at the file already holding the content.
+  Skip,
+}
+
+/// Keeps one copy of every content across accounts and platforms, by BLAKE3 hash.
+#[derive(Serialize, Deserialize)]
+pub struct DedupConfig {
+  policy: Option<Policy>,
+  /// Where hashes and the first file holding them are kept between runs, default is
+  /// `./.content.jsonl`.
+  store: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+  hash: String,
+  path: PathBuf,
+}
+
+pub struct ContentStore {
+  policy: Policy,
+  known: Mutex<HashMap<String, PathBuf>>,
+  file: Mutex<BufWriter<File>>,
+}
+
+impl ContentStore {
+  pub async fn open(config: &DedupConfig) -> io::Result<Self> {
+    let path = config.store.as_deref().unwrap_or(STORE);
+    let known = match tokio::fs::read_to_string(path).await {
+      Ok(raw) => raw.lines()
+        .filter_map(|line| from_str::<Entry>(line).ok())
+        .map(|entry| (entry.hash, entry.path))
+        .collect(),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+      Err(err) => return Err(err),
+    };
+    let file = OpenOptions::new().create(true).append(true).open(path).await?;
+    Ok(Self {
+      policy: config.policy.unwrap_or_default(),
+      known: Mutex::new(known),
+      file: Mutex::new(BufWriter::new(file)),
+    })
+  }
+
+  pub fn policy(&self) -> Policy {
+    self.policy
+  }
+
+  pub fn hash(bytes: &[u8]) -> String {
+    blake3::hash(bytes).to_hex().to_string()
+  }
+
+  /// The file already holding `hash`, or `None` after recording `path` as its holder.
+  pub async fn claim(&self, hash: &str, path: &Path) -> Option<PathBuf> {
+    let mut known = self.known.lock().await;
+    if let Some(existing) = known.get(hash).filter(|existing| existing.is_file()) {
+      return Some(existing.clone());
+    }
+    let path = absolute(path).unwrap_or_else(|_| path.to_owned());
+    let line = to_string(&Entry { hash: hash.to_owned(), path: path.clone() }).unwrap() + "\n";
+    if self.file.lock().await.write_all(line.as_bytes()).await.is_err() {
+      message!("IO error in content store, {} will not be deduplicated next run.", path.display());
+    }
+    known.insert(hash.to_owned(), path);
+    None
+  }
+
+  /// Makes `path` hold the content of `existing` by the configured policy.
+  pub async fn place(&self, existing: &Path, path: &Path) -> io::Result<()> {
+    match self.policy {
+      Policy::Hardlink => tokio::fs::hard_link(existing, path).await,
+      Policy::Reflink => reflink_copy::reflink_or_copy(existing, path).map(|_| ()),
+      #[cfg(unix)]
+      Policy::Symlink => tokio::fs::symlink(existing, path).await,
+      #[cfg(windows)]
+      Policy::Symlink => tokio::fs::symlink_file(existing, path).await,
+      Policy::Skip => Ok(()),
+    }
+  }
+
+  pub async fn flush(&self) {
+    if self.file.lock().await.flush().await.is_err() {
+      message!("IO error in content store, hashes may be lost.");
+    }
+  }
+}
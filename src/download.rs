@@ -0,0 +1,337 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use serde::Deserialize;
+use serde_json::{from_value, Value};
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use crate::adapters::{Adapters, Item, MediaKind};
+use crate::convert::ConvertGif;
+use crate::dedup::{ContentStore, Policy};
+use crate::embed::{embed, Metadata};
+use crate::events::{emit, Event};
+use crate::manifest::{self, Manifest, Record, Status};
+use crate::phash::dhash;
+use crate::sanitize::{normalize, Sanitizer, MAX_BYTES};
+use crate::shutdown::Shutdown;
+use crate::sidecar::SidecarConfig;
+use crate::template::Template;
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TextOutput {
+  /// `<file>.txt` next to every downloaded file.
+  Txt,
+  /// `alt_text` and `post_text` fields in the manifest.
+  Manifest,
+}
+
+/// Suffix of files still being written.
+const PART: &str = ".part";
+
+/// Options shared by every platform, read from the same object as the account itself.
+#[derive(Deserialize, Default)]
+pub struct AccountOptions {
+  /// Enables or disables media kinds by name, card images are disabled by default.
+  media: Option<HashMap<String, bool>>,
+  convert_gif: Option<ConvertGif>,
+  /// Path of each file relative to the account path, see [`Template`].
+  #[serde(default)]
+  filename: Template,
+  /// Longest file or directory name in bytes, default is 255.
+  max_filename_bytes: Option<usize>,
+  sidecar: Option<SidecarConfig>,
+  /// Writes the source url, author, text, alt text and date into the files themselves, default
+  /// is false.
+  embed_metadata: Option<bool>,
+  /// Records a perceptual hash and the size of every image in the manifest, default is false.
+  perceptual_hash: Option<bool>,
+  /// Where to keep the alt text and post text of each item, dropped by default.
+  text: Option<TextOutput>,
+}
+
+impl AccountOptions {
+  fn accepts(&self, kind: &MediaKind) -> bool {
+    self.media.as_ref()
+      .and_then(|media| media.get(kind.name()).copied())
+      .unwrap_or(*kind != MediaKind::CardImage)
+  }
+
+  /// The conversion applied to `item`, if any.
+  fn convert(&self, item: &dyn Item) -> Option<&ConvertGif> {
+    self.convert_gif.as_ref().filter(|_| *item.kind() == MediaKind::Gif)
+  }
+}
+
+/// Where an account saves its items, and what is already there.
+pub struct Account {
+  pub platform: &'static str,
+  pub name: String,
+  pub path: String,
+  options: AccountOptions,
+  manifest: Manifest,
+  existing: HashSet<String>,
+  sanitizer: Sanitizer,
+  ffmpeg: String,
+  store: Option<Arc<ContentStore>>,
+  shutdown: Arc<Shutdown>,
+}
+
+/// What every account shares.
+pub struct Shared {
+  pub ffmpeg: String,
+  pub store: Option<Arc<ContentStore>>,
+  pub shutdown: Arc<Shutdown>,
+  /// Plans downloads without touching the account path.
+  pub dry_run: bool,
+}
+
+impl Account {
+  pub async fn open(adapter: &dyn Adapters, config: &Value, shared: &Shared) -> Self {
+    let options: AccountOptions = from_value(config.clone()).unwrap();
+
+    let mut existing = HashSet::<String>::new();
+    walk(Path::new(adapter.path()), "", &mut existing, shared.dry_run);
+    // Files skipped or linked as duplicates may be missing on disk, they still count as done, and
+    // unsupported items are only recorded once.
+    existing.extend(manifest::read(adapter.path()).into_iter()
+      .filter(|entry| matches!(entry.status, Status::Removed | Status::Duplicate | Status::Linked | Status::Unsupported))
+      .map(|entry| entry.filename));
+    let manifest = if shared.dry_run {
+      Manifest::disabled()
+    } else {
+      let _ = fs::create_dir_all(adapter.path());
+      Manifest::open(adapter.path()).await.unwrap()
+    };
+
+    Self {
+      platform: adapter.platform(),
+      name: adapter.name().to_owned(),
+      path: adapter.path().to_owned(),
+      manifest,
+      ffmpeg: shared.ffmpeg.clone(),
+      sanitizer: Sanitizer::new(options.max_filename_bytes.unwrap_or(MAX_BYTES), &existing),
+      options,
+      existing,
+      store: shared.store.clone(),
+      shutdown: shared.shutdown.clone(),
+    }
+  }
+
+  /// The path of `item` relative to the account path, safe to create on any platform.
+  pub fn filename(&self, item: &dyn Item) -> String {
+    self.sanitizer.sanitize(&self.options.filename.render(item))
+  }
+
+  /// Whether `item` passes the media filter and is not downloaded yet.
+  pub fn wants(&self, item: &dyn Item) -> bool {
+    self.accepts(item) && !self.exists(item)
+  }
+
+  /// Whether `item` passes the media filter.
+  pub fn accepts(&self, item: &dyn Item) -> bool {
+    self.options.accepts(item.kind())
+  }
+
+  /// Whether `item` is already in the account path, as itself or converted.
+  pub fn exists(&self, item: &dyn Item) -> bool {
+    let filename = self.filename(item);
+    self.existing.contains(&filename)
+      || self.options.convert(item).is_some_and(|convert| self.existing.contains(&convert.target(&filename)))
+  }
+
+  /// Downloads `item` into the account path and records the outcome in the manifest. Nothing is
+  /// saved or recorded when a shutdown is requested before the download finishes.
+  pub async fn download(&self, item: &dyn Item) -> Status {
+    let filename = self.filename(item);
+    if let MediaKind::Unknown(media_type) = item.kind() {
+      message!("Warning: unknown media type {}, url {}, skipped.", media_type, item.url());
+      self.failed(item, &filename, &format!("unknown media type {media_type}"));
+      self.manifest.write(&self.record(item, &filename, Status::Unsupported)).await;
+      return Status::Unsupported;
+    }
+
+    let path = Path::new(&self.path).join(&filename);
+    if let Some(parent) = path.parent() {
+      let _ = tokio::fs::create_dir_all(parent).await;
+    }
+    emit(Event::DownloadStarted {
+      platform: self.platform,
+      account: &self.name,
+      filename: &filename,
+      media_url: item.media_url(),
+    });
+    let mut bytes = tokio::select! {
+      bytes = item.get() => bytes,
+      _ = self.shutdown.requested() => return Status::Cancelled,
+    };
+    let size = bytes.len();
+    if size == 0 {
+      message!("Warning: empty download from {}, skipped.", item.media_url());
+      self.failed(item, &filename, "empty download");
+      self.manifest.write(&self.record(item, &filename, Status::Failed)).await;
+      return Status::Failed;
+    }
+    let hash = self.store.as_ref().map(|_| ContentStore::hash(&bytes));
+    let image = matches!(item.kind(), MediaKind::Photo | MediaKind::CardImage);
+    let dhash = if image && self.options.perceptual_hash.unwrap_or(false) {
+      let bytes = bytes.clone();
+      tokio::task::spawn_blocking(move || dhash(&bytes)).await.unwrap()
+    } else {
+      None
+    };
+    let mut duplicate_of = None;
+    if let (Some(store), Some(hash)) = (&self.store, &hash) {
+      duplicate_of = store.claim(hash, &path).await;
+    }
+    let status = match (&self.store, &duplicate_of) {
+      (Some(store), Some(_)) if store.policy() == Policy::Skip => Status::Duplicate,
+      (Some(store), Some(existing)) if store.place(existing, &path).await.is_ok() => Status::Linked,
+      _ => {
+        if self.options.embed_metadata.unwrap_or(false) {
+          bytes = embed(bytes, item.ext(), &Metadata::new(item));
+        }
+        self.write(item, &path, &filename, &mut bytes).await
+      }
+    };
+    let saved_file = matches!(status, Status::Downloaded | Status::Linked);
+    let mut saved = filename.clone();
+    if let (true, Some(convert)) = (saved_file, self.options.convert(item)) {
+      match convert.convert(&self.ffmpeg, &path).await {
+        Ok(target) => {
+          if let Some(timestamp) = item.timestamp() {
+            let _ = fs::File::options().write(true).open(target).and_then(|f| f.set_modified(timestamp.into()));
+          }
+          if !convert.keep_original() {
+            let _ = tokio::fs::remove_file(&path).await;
+            saved = convert.target(&filename);
+          }
+        }
+        Err(err) => message!("Cannot convert {}, {}, original kept.", filename, err),
+      }
+    }
+    if let (true, Some(sidecar)) = (saved_file, &self.options.sidecar) {
+      sidecar.write(&self.path, item, &saved).await;
+    }
+    if let (true, Some(TextOutput::Txt)) = (saved_file, self.options.text) {
+      write_text(&Path::new(&self.path).join(format!("{saved}.txt")), item).await;
+    }
+    let duplicate_of = duplicate_of.map(|v| v.to_string_lossy().into_owned());
+    let mut record = self.record(item, &filename, status);
+    record.hash = hash.as_deref();
+    record.duplicate_of = duplicate_of.as_deref();
+    let phash = dhash.map(|(phash, _, _)| format!("{phash:016x}"));
+    if status != Status::Duplicate {
+      record.phash = phash.as_deref();
+      record.width = dhash.map(|(_, width, _)| width);
+      record.height = dhash.map(|(_, _, height)| height);
+    }
+    self.manifest.write(&record).await;
+    if status != Status::Failed {
+      emit(Event::DownloadFinished { platform: self.platform, account: &self.name, filename: &filename, status, bytes: size });
+    }
+    status
+  }
+
+  fn failed(&self, item: &dyn Item, filename: &str, error: &str) {
+    emit(Event::DownloadFailed {
+      platform: self.platform,
+      account: &self.name,
+      filename,
+      media_url: item.media_url(),
+      error,
+    });
+  }
+
+  /// Writes `bytes` to `path` and dates the file after the post. The bytes go to a `.part` file
+  /// first, so `path` never holds half a file.
+  async fn write(&self, item: &dyn Item, path: &Path, filename: &str, bytes: &mut Bytes) -> Status {
+    let mut part = path.as_os_str().to_owned();
+    part.push(PART);
+    let part = PathBuf::from(part);
+    let Ok(mut file) = File::create(&part).await else {
+      message!("Cannot create file {}, url {}, skipped.", filename, item.media_url());
+      self.failed(item, filename, "cannot create file");
+      return Status::Failed;
+    };
+    let result = file.write_all_buf(bytes).await;
+    if result.is_err() || file.flush().await.is_err() {
+      message!("IO error in {}, url {}.", filename, item.media_url());
+      self.failed(item, filename, "io error");
+      drop(file);
+      let _ = tokio::fs::remove_file(&part).await;
+      return Status::Failed;
+    }
+    if let Some(timestamp) = item.timestamp() {
+      let _ = file.into_std().await.set_modified(timestamp.into());
+    }
+    if let Err(err) = tokio::fs::rename(&part, path).await {
+      message!("Cannot create file {}, {}, skipped.", filename, err);
+      self.failed(item, filename, &err.to_string());
+      let _ = tokio::fs::remove_file(&part).await;
+      return Status::Failed;
+    }
+    Status::Downloaded
+  }
+
+  fn record<'a>(&'a self, item: &'a dyn Item, filename: &'a str, status: Status) -> Record<'a> {
+    let record = Record::new(self.platform, &self.name, item, filename, status);
+    match self.options.text {
+      Some(TextOutput::Manifest) => record.with_text(item),
+      _ => record,
+    }
+  }
+
+  /// The records of the manifest of this account.
+  pub fn entries(&self) -> Vec<manifest::Entry> {
+    manifest::read(&self.path)
+  }
+
+  /// Deletes the file of `entry` and records it, so it is not downloaded again.
+  pub async fn remove(&self, entry: &manifest::Entry) {
+    let path = Path::new(&self.path).join(&entry.filename);
+    if let Err(err) = tokio::fs::remove_file(&path).await {
+      message!("Cannot remove {}, {}.", path.display(), err);
+      return;
+    }
+    self.manifest.write(&Record::removed(entry)).await;
+  }
+
+  pub async fn flush(&self) {
+    self.manifest.flush().await;
+  }
+}
+
+/// Writes the post text of `item`, followed by its alt text if there is one.
+async fn write_text(path: &Path, item: &dyn Item) {
+  let mut text = item.post_text().to_owned();
+  if let Some(alt_text) = item.alt_text() {
+    text = format!("{text}\n\nAlt text: {alt_text}");
+  }
+  if tokio::fs::write(path, text).await.is_err() {
+    message!("Cannot create text file {}, skipped.", path.display());
+  }
+}
+
+/// Collects every file under `dir` as a path relative to the account path, joined by `/`.
+/// `.part` files left by a forced exit are removed instead, unless `keep_parts` is set.
+fn walk(dir: &Path, prefix: &str, set: &mut HashSet<String>, keep_parts: bool) {
+  let Ok(entries) = fs::read_dir(dir) else {
+    return;
+  };
+  for entry in entries.flatten() {
+    let name = normalize(&entry.file_name().to_string_lossy());
+    let relative = if prefix.is_empty() { name } else { format!("{prefix}/{name}") };
+    if entry.file_type().is_ok_and(|t| t.is_dir()) {
+      walk(&entry.path(), &relative, set, keep_parts);
+    } else if relative.ends_with(PART) && !keep_parts {
+      let _ = fs::remove_file(entry.path());
+    } else {
+      set.insert(relative);
+    }
+  }
+}
@@ -0,0 +1,114 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::download::Account;
+use crate::manifest::{Entry, Status};
+use crate::phash::distance;
+
+/// Hamming distance below or at which two images count as the same, out of 64 bits.
+pub const THRESHOLD: u32 = 10;
+
+struct Image {
+  account: usize,
+  entry: Entry,
+  hash: u64,
+  /// Pixel count, then file size, the larger copy is the better one.
+  size: (u64, u64),
+  /// Other files are links to this one, so it is never removed.
+  linked_to: bool,
+}
+
+fn find(parent: &mut [usize], i: usize) -> usize {
+  let mut root = i;
+  while parent[root] != root {
+    root = parent[root];
+  }
+  parent[i] = root;
+  root
+}
+
+/// Prints clusters of near identical images across `accounts`, by the perceptual hashes in
+/// their manifests. With `keep_best`, every copy but the largest is deleted, or only marked as
+/// such in a `dry_run`. Symlinks are not copies of their own, and files other files are linked
+/// to are kept.
+pub async fn run(accounts: &[Arc<Account>], threshold: u32, keep_best: bool, dry_run: bool) {
+  let mut entries = Vec::new();
+  let mut targets = HashSet::new();
+  for a in accounts {
+    let mut latest = HashMap::new();
+    for entry in a.entries() {
+      targets.extend(entry.duplicate_of.clone().map(PathBuf::from));
+      latest.insert(entry.filename.clone(), entry);
+    }
+    entries.push(latest);
+  }
+
+  let mut images = Vec::new();
+  for (account, latest) in entries.into_iter().enumerate() {
+    let a = &accounts[account];
+    for entry in latest.into_values() {
+      let Some(hash) = entry.phash.as_deref().and_then(|v| u64::from_str_radix(v, 16).ok()) else {
+        continue;
+      };
+      if !matches!(entry.status, Status::Downloaded | Status::Linked) {
+        continue;
+      }
+      let path = Path::new(&a.path).join(&entry.filename);
+      let Ok(metadata) = std::fs::symlink_metadata(&path) else {
+        continue;
+      };
+      if metadata.is_symlink() {
+        continue;
+      }
+      let pixels = entry.width.unwrap_or(0) as u64 * entry.height.unwrap_or(0) as u64;
+      let linked_to = targets.contains(&path);
+      images.push(Image { account, entry, hash, size: (pixels, metadata.len()), linked_to });
+    }
+  }
+
+  let mut parent: Vec<_> = (0..images.len()).collect();
+  for i in 0..images.len() {
+    for j in i + 1..images.len() {
+      if distance(images[i].hash, images[j].hash) <= threshold {
+        let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+        parent[a] = b;
+      }
+    }
+  }
+  let mut clusters = HashMap::<usize, Vec<&Image>>::new();
+  for (i, image) in images.iter().enumerate() {
+    clusters.entry(find(&mut parent, i)).or_default().push(image);
+  }
+  let mut clusters: Vec<_> = clusters.into_values().filter(|v| v.len() > 1).collect();
+  for cluster in &mut clusters {
+    cluster.sort_by_key(|image| (Reverse((image.size, image.linked_to)), image.account, image.entry.filename.clone()));
+  }
+  clusters.sort_by_key(|cluster| Reverse(cluster.len()));
+
+  for (n, cluster) in clusters.iter().enumerate() {
+    message!("Cluster {}, {} images:", n + 1, cluster.len());
+    for (i, image) in cluster.iter().enumerate() {
+      let account = &accounts[image.account];
+      let path = Path::new(&account.path).join(&image.entry.filename);
+      let (width, height) = (image.entry.width.unwrap_or(0), image.entry.height.unwrap_or(0));
+      if keep_best && i > 0 && image.linked_to {
+        message!("  {width}x{height} {} (kept, other files link to it)", path.display());
+      } else if keep_best && i > 0 && dry_run {
+        message!("  {width}x{height} {} (would be removed)", path.display());
+      } else if keep_best && i > 0 {
+        account.remove(&image.entry).await;
+        message!("  {width}x{height} {} (removed)", path.display());
+      } else {
+        message!("  {width}x{height} {}", path.display());
+      }
+    }
+  }
+  if clusters.is_empty() {
+    message!("No near duplicates found.");
+  }
+  for account in accounts {
+    account.flush().await;
+  }
+}
@@ -0,0 +1,520 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::adapters::Item;
+
+/// What gets written into a file, taken from the post of an item.
+pub struct Metadata<'a> {
+  pub source: &'a str,
+  pub author: String,
+  pub text: &'a str,
+  pub alt_text: Option<&'a str>,
+  pub created_at: Option<DateTime<Utc>>,
+}
+
+impl<'a> Metadata<'a> {
+  pub fn new(item: &'a dyn Item) -> Self {
+    let post = item.post();
+    let author = if post.author_name.is_empty() {
+      post.author.clone()
+    } else {
+      format!("{} (@{})", post.author_name, post.author)
+    };
+    Self {
+      source: &post.url,
+      author,
+      text: item.post_text(),
+      alt_text: item.alt_text(),
+      created_at: item.timestamp(),
+    }
+  }
+}
+
+/// Writes `metadata` into `bytes` by the container format of `ext`.
+///
+/// Images get an XMP packet, MP4 gets an iTunes style `ilst`. Other formats and files that do
+/// not parse are returned unchanged.
+pub fn embed(bytes: Bytes, ext: &str, metadata: &Metadata) -> Bytes {
+  let result = match ext.to_ascii_lowercase().as_str() {
+    "jpg" | "jpeg" => jpeg(&bytes, &xmp(metadata)),
+    "png" => png(&bytes, &xmp(metadata)),
+    "webp" => webp(&bytes, &xmp(metadata)),
+    "mp4" | "m4v" | "mov" => mp4(&bytes, metadata),
+    _ => return bytes,
+  };
+  match result {
+    Some(embedded) => embedded.freeze(),
+    None => {
+      message!("Warning: cannot embed metadata into {} file from {}, saved as is.", ext, metadata.source);
+      bytes
+    }
+  }
+}
+
+fn escape(text: &str) -> String {
+  text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn xmp(metadata: &Metadata) -> String {
+  let alt = |tag: &str, text: &str| {
+    format!("<{tag}><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></{tag}>", escape(text))
+  };
+  let mut fields = vec![
+    format!("<dc:source>{}</dc:source>", escape(metadata.source)),
+    format!("<dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>", escape(&metadata.author)),
+    alt("dc:description", metadata.text),
+  ];
+  if let Some(alt_text) = metadata.alt_text {
+    fields.push(alt("Iptc4xmpCore:AltTextAccessibility", alt_text));
+  }
+  if let Some(created_at) = metadata.created_at {
+    let date = created_at.to_rfc3339_opts(SecondsFormat::Secs, true);
+    fields.push(format!("<xmp:CreateDate>{date}</xmp:CreateDate>"));
+    fields.push(format!("<photoshop:DateCreated>{date}</photoshop:DateCreated>"));
+  }
+  format!(
+    concat!(
+      "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>",
+      "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+      "<rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\"",
+      " xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" xmlns:photoshop=\"http://ns.adobe.com/photoshop/1.0/\"",
+      " xmlns:Iptc4xmpCore=\"http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/\">{}</rdf:Description>",
+      "</rdf:RDF></x:xmpmeta><?xpacket end=\"w\"?>",
+    ),
+    fields.concat()
+  )
+}
+
+/// Adds an APP1 XMP segment after SOI, or after the JFIF APP0 segment that must come first.
+fn jpeg(data: &[u8], xmp: &str) -> Option<BytesMut> {
+  const NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+  if !data.starts_with(&[0xff, 0xd8]) {
+    return None;
+  }
+  let mut at = 2;
+  if data.get(2..4)? == [0xff, 0xe0] {
+    at += 2 + u16::from_be_bytes([*data.get(4)?, *data.get(5)?]) as usize;
+  }
+  let length = u16::try_from(2 + NAMESPACE.len() + xmp.len()).ok()?;
+
+  let mut out = BytesMut::with_capacity(data.len() + length as usize + 2);
+  out.put_slice(data.get(..at)?);
+  out.put_slice(&[0xff, 0xe1]);
+  out.put_u16(length);
+  out.put_slice(NAMESPACE);
+  out.put_slice(xmp.as_bytes());
+  out.put_slice(&data[at..]);
+  Some(out)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+  let mut crc = 0xffffffffu32;
+  for byte in bytes {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+    }
+  }
+  !crc
+}
+
+/// Adds an `iTXt` chunk with the XMP keyword right after `IHDR`.
+fn png(data: &[u8], xmp: &str) -> Option<BytesMut> {
+  const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+  if !data.starts_with(SIGNATURE) || data.get(12..16)? != b"IHDR" {
+    return None;
+  }
+  let at = 8 + 12 + u32::from_be_bytes(data.get(8..12)?.try_into().ok()?) as usize;
+
+  let mut chunk = BytesMut::new();
+  chunk.put_slice(b"iTXt");
+  chunk.put_slice(b"XML:com.adobe.xmp\0\0\0\0\0");
+  chunk.put_slice(xmp.as_bytes());
+
+  let mut out = BytesMut::with_capacity(data.len() + chunk.len() + 8);
+  out.put_slice(data.get(..at)?);
+  out.put_u32(chunk.len() as u32 - 4);
+  out.put_slice(&chunk);
+  out.put_u32(crc32(&chunk));
+  out.put_slice(&data[at..]);
+  Some(out)
+}
+
+/// Adds an `XMP ` chunk, turning a simple lossy or lossless file into the extended format
+/// with a `VP8X` chunk first, as the XMP flag lives there.
+fn webp(data: &[u8], xmp: &str) -> Option<BytesMut> {
+  if data.get(..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+    return None;
+  }
+  let first = data.get(12..16)?;
+  let payload = data.get(20..)?;
+  let vp8x = match first {
+    b"VP8X" => None,
+    b"VP8 " => {
+      let width = u16::from_le_bytes([*payload.get(6)?, *payload.get(7)?]) as u32 & 0x3fff;
+      let height = u16::from_le_bytes([*payload.get(8)?, *payload.get(9)?]) as u32 & 0x3fff;
+      Some((0u8, width.checked_sub(1)?, height.checked_sub(1)?))
+    }
+    b"VP8L" => {
+      let bits = u32::from_le_bytes(payload.get(1..5)?.try_into().ok()?);
+      let alpha = if bits & (1 << 28) != 0 { 0x10 } else { 0 };
+      Some((alpha, bits & 0x3fff, (bits >> 14) & 0x3fff))
+    }
+    _ => return None,
+  };
+
+  let mut out = BytesMut::with_capacity(data.len() + xmp.len() + 40);
+  out.put_slice(b"RIFF\0\0\0\0WEBP");
+  match vp8x {
+    Some((flags, width, height)) => {
+      out.put_slice(b"VP8X");
+      out.put_u32_le(10);
+      out.put_u8(flags | 0x04);
+      out.put_slice(&[0, 0, 0]);
+      out.put_slice(&width.to_le_bytes()[..3]);
+      out.put_slice(&height.to_le_bytes()[..3]);
+      out.put_slice(&data[12..]);
+    }
+    None => {
+      out.put_slice(&data[12..]);
+      out[20] |= 0x04;
+    }
+  }
+  if out.len() % 2 == 1 {
+    out.put_u8(0);
+  }
+  out.put_slice(b"XMP ");
+  out.put_u32_le(xmp.len() as u32);
+  out.put_slice(xmp.as_bytes());
+  if xmp.len() % 2 == 1 {
+    out.put_u8(0);
+  }
+  let size = (out.len() - 8) as u32;
+  out[4..8].copy_from_slice(&size.to_le_bytes());
+  Some(out)
+}
+
+/// A box of an MP4 file, as positions in the data it was read from.
+pub struct Mp4Box {
+  pub kind: [u8; 4],
+  pub start: usize,
+  /// Where the content starts, after the size, type and large size.
+  pub body: usize,
+  pub end: usize,
+}
+
+/// The boxes between `start` and `end`, `None` when their sizes do not add up.
+pub fn boxes(data: &[u8], start: usize, end: usize) -> Option<Vec<Mp4Box>> {
+  let mut boxes = Vec::new();
+  let mut at = start;
+  while at + 8 <= end {
+    let size = u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?) as usize;
+    let kind: [u8; 4] = data.get(at + 4..at + 8)?.try_into().ok()?;
+    let (body, size) = match size {
+      0 => (at + 8, end - at),
+      1 => (at + 16, usize::try_from(u64::from_be_bytes(data.get(at + 8..at + 16)?.try_into().ok()?)).ok()?),
+      size => (at + 8, size),
+    };
+    let box_end = at.checked_add(size)?;
+    if size < body - at || box_end > end {
+      return None;
+    }
+    boxes.push(Mp4Box { kind, start: at, body, end: box_end });
+    at = box_end;
+  }
+  Some(boxes)
+}
+
+pub fn mp4_box(kind: &[u8; 4], content: &[u8]) -> BytesMut {
+  let mut out = BytesMut::with_capacity(content.len() + 8);
+  out.put_u32((content.len() + 8) as u32);
+  out.put_slice(kind);
+  out.put_slice(content);
+  out
+}
+
+/// Shifts the chunk offsets in `stco` and `co64` of every track by `delta`.
+fn patch_offsets(data: &mut [u8], start: usize, end: usize, delta: u64) -> Option<()> {
+  for child in boxes(data, start, end)? {
+    match &child.kind {
+      b"trak" | b"mdia" | b"minf" | b"stbl" => patch_offsets(data, child.body, child.end, delta)?,
+      b"stco" | b"co64" => {
+        let width = if &child.kind == b"stco" { 4 } else { 8 };
+        let count = u32::from_be_bytes(data.get(child.body + 4..child.body + 8)?.try_into().ok()?) as usize;
+        for i in 0..count {
+          let at = child.body + 8 + i * width;
+          let entry = data.get_mut(at..at + width)?;
+          if width == 4 {
+            let offset = u32::from_be_bytes(entry.try_into().ok()?) as u64 + delta;
+            entry.copy_from_slice(&u32::try_from(offset).ok()?.to_be_bytes());
+          } else {
+            let offset = u64::from_be_bytes(entry.try_into().ok()?) + delta;
+            entry.copy_from_slice(&offset.to_be_bytes());
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+  Some(())
+}
+
+/// Whether growing `moov` would break absolute offsets of a fragmented file: a `tfhd` with
+/// base-data-offset-present in a later `moof`, or the `tfra` entries of `mfra`.
+fn absolute_fragments(data: &[u8], top: &[Mp4Box], moov: &Mp4Box) -> Option<bool> {
+  for b in top.iter().filter(|b| b.start > moov.start) {
+    match &b.kind {
+      b"mfra" => return Some(true),
+      b"moof" => {
+        for traf in boxes(data, b.body, b.end)?.iter().filter(|v| &v.kind == b"traf") {
+          for tfhd in boxes(data, traf.body, traf.end)?.iter().filter(|v| &v.kind == b"tfhd") {
+            if data.get(tfhd.body + 3)? & 1 != 0 {
+              return Some(true);
+            }
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+  Some(false)
+}
+
+/// Adds `moov/udta/meta/ilst` with `©ART`, `©cmt`, `desc`, `ldes` and `©day`.
+///
+/// When `moov` comes before the media data, the chunk offsets are moved by the added size.
+/// Fragmented files with absolute offsets after `moov` are left as they are.
+fn mp4(data: &[u8], metadata: &Metadata) -> Option<BytesMut> {
+  let top = boxes(data, 0, data.len())?;
+  let moov = top.iter().find(|b| &b.kind == b"moov")?;
+  if absolute_fragments(data, &top, moov)? {
+    return None;
+  }
+
+  let mut items = vec![
+    (*b"\xa9ART", metadata.author.clone()),
+    (*b"\xa9cmt", metadata.source.to_owned()),
+    (*b"desc", metadata.text.to_owned()),
+  ];
+  if let Some(alt_text) = metadata.alt_text {
+    items.push((*b"ldes", alt_text.to_owned()));
+  }
+  if let Some(created_at) = metadata.created_at {
+    items.push((*b"\xa9day", created_at.to_rfc3339_opts(SecondsFormat::Secs, true)));
+  }
+  let mut ilst = BytesMut::new();
+  for (kind, text) in items {
+    let mut data = BytesMut::new();
+    data.put_u32(1);
+    data.put_u32(0);
+    data.put_slice(text.as_bytes());
+    ilst.extend(mp4_box(&kind, &mp4_box(b"data", &data)));
+  }
+  let mut meta = BytesMut::new();
+  meta.put_u32(0);
+  meta.extend(mp4_box(b"hdlr", b"\0\0\0\0\0\0\0\0mdirappl\0\0\0\0\0\0\0\0\0"));
+  meta.extend(mp4_box(b"ilst", &ilst));
+  let meta = mp4_box(b"meta", &meta);
+
+  let mut content = BytesMut::new();
+  let mut has_udta = false;
+  for child in boxes(data, moov.body, moov.end)? {
+    if &child.kind == b"udta" && !has_udta {
+      has_udta = true;
+      let mut udta = BytesMut::from(&data[child.body..child.end]);
+      udta.extend_from_slice(&meta);
+      content.extend(mp4_box(b"udta", &udta));
+    } else {
+      content.extend_from_slice(&data[child.start..child.end]);
+    }
+  }
+  if !has_udta {
+    content.extend(mp4_box(b"udta", &meta));
+  }
+  let mut new_moov = mp4_box(b"moov", &content);
+
+  let delta = new_moov.len() as u64 - (moov.end - moov.start) as u64;
+  let data_after = top.iter().any(|b| &b.kind == b"mdat" && b.start > moov.start);
+  if data_after {
+    let len = new_moov.len();
+    patch_offsets(&mut new_moov, 8, len, delta)?;
+  }
+
+  let mut out = BytesMut::with_capacity(data.len() + delta as usize);
+  out.put_slice(&data[..moov.start]);
+  out.extend(new_moov);
+  out.put_slice(&data[moov.end..]);
+  Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use image::{DynamicImage, ImageFormat, RgbImage};
+
+  use super::*;
+
+  fn metadata() -> Metadata<'static> {
+    Metadata {
+      source: "https://x.com/a/status/1",
+      author: "A (@a)".to_owned(),
+      text: "text & <tags>",
+      alt_text: Some("alt"),
+      created_at: DateTime::from_timestamp(1_700_000_000, 0),
+    }
+  }
+
+  fn encode(format: ImageFormat) -> Bytes {
+    let image = DynamicImage::ImageRgb8(RgbImage::from_fn(4, 3, |x, y| [x as u8 * 60, y as u8 * 80, 128].into()));
+    let mut out = Cursor::new(Vec::new());
+    image.write_to(&mut out, format).unwrap();
+    out.into_inner().into()
+  }
+
+  fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|v| v == needle)
+  }
+
+  #[test]
+  fn jpeg_keeps_decoding() {
+    let data = encode(ImageFormat::Jpeg);
+    let out = embed(data.clone(), "jpg", &metadata());
+    assert_eq!(out.len(), data.len() + 4 + 29 + xmp(&metadata()).len());
+    assert!(contains(&out, b"http://ns.adobe.com/xap/1.0/\0<?xpacket"));
+    assert!(contains(&out, b"text &amp; &lt;tags&gt;"));
+    let image = image::load_from_memory(&out).unwrap();
+    assert_eq!((image.width(), image.height()), (4, 3));
+  }
+
+  #[test]
+  fn png_chunk_has_valid_crc() {
+    let out = embed(encode(ImageFormat::Png), "png", &metadata());
+    let at = 8 + 12 + 13;
+    let length = u32::from_be_bytes(out[at..at + 4].try_into().unwrap()) as usize;
+    let chunk = &out[at + 4..at + 8 + length];
+    assert!(chunk.starts_with(b"iTXtXML:com.adobe.xmp\0"));
+    assert_eq!(out[at + 8 + length..at + 12 + length], crc32(chunk).to_be_bytes());
+    let image = image::load_from_memory(&out).unwrap();
+    assert_eq!((image.width(), image.height()), (4, 3));
+  }
+
+  #[test]
+  fn webp_lossless_becomes_extended() {
+    let data = encode(ImageFormat::WebP);
+    assert_eq!(&data[12..16], b"VP8L");
+    let out = embed(data, "webp", &metadata());
+    assert_eq!(&out[12..16], b"VP8X");
+    assert_eq!(out[20] & 0x04, 0x04);
+    assert_eq!((&out[24..27], &out[27..30]), (&[3, 0, 0][..], &[2, 0, 0][..]));
+    assert_eq!(u32::from_le_bytes(out[4..8].try_into().unwrap()) as usize, out.len() - 8);
+    assert!(contains(&out, b"XMP "));
+    let image = image::load_from_memory(&out).unwrap();
+    assert_eq!((image.width(), image.height()), (4, 3));
+  }
+
+  #[test]
+  fn webp_lossy_header_size() {
+    let mut vp8 = vec![0, 0, 0, 0x9d, 0x01, 0x2a];
+    vp8.extend_from_slice(&640u16.to_le_bytes());
+    vp8.extend_from_slice(&(480u16 | 0x4000).to_le_bytes());
+    let mut data = BytesMut::new();
+    data.put_slice(b"RIFF");
+    data.put_u32_le(4 + 8 + vp8.len() as u32);
+    data.put_slice(b"WEBPVP8 ");
+    data.put_u32_le(vp8.len() as u32);
+    data.put_slice(&vp8);
+    let out = webp(&data, "<x/>").unwrap();
+    assert_eq!(&out[12..16], b"VP8X");
+    assert_eq!(out[20], 0x04);
+    assert_eq!(u32::from_le_bytes([out[24], out[25], out[26], 0]), 639);
+    assert_eq!(u32::from_le_bytes([out[27], out[28], out[29], 0]), 479);
+    assert_eq!(&out[30..34], b"VP8 ");
+  }
+
+  /// `ftyp`, then `moov` with one track whose `stco` points into `mdat`, which comes after or
+  /// before `moov`.
+  fn mp4_file(mdat_first: bool) -> (Vec<u8>, Vec<u32>) {
+    let ftyp = mp4_box(b"ftyp", b"isom\0\0\0\0isom");
+    let mdat = mp4_box(b"mdat", &[7; 32]);
+    let stco = |offsets: &[u32]| {
+      let mut content = BytesMut::new();
+      content.put_u32(0);
+      content.put_u32(offsets.len() as u32);
+      for offset in offsets {
+        content.put_u32(*offset);
+      }
+      let stbl = mp4_box(b"stbl", &mp4_box(b"stco", &content));
+      let trak = mp4_box(b"trak", &mp4_box(b"mdia", &mp4_box(b"minf", &stbl)));
+      let mut moov = mp4_box(b"mvhd", &[0; 100]);
+      moov.extend(trak);
+      mp4_box(b"moov", &moov)
+    };
+    let moov_len = stco(&[0, 0]).len();
+    let data_at = ftyp.len() + if mdat_first { 0 } else { moov_len } + 8;
+    let offsets = vec![data_at as u32, data_at as u32 + 16];
+    let mut file = ftyp.to_vec();
+    if mdat_first {
+      file.extend_from_slice(&mdat);
+      file.extend_from_slice(&stco(&offsets));
+    } else {
+      file.extend_from_slice(&stco(&offsets));
+      file.extend_from_slice(&mdat);
+    }
+    (file, offsets)
+  }
+
+  fn offsets(data: &[u8]) -> Vec<u32> {
+    let at = data.windows(4).position(|v| v == b"stco").unwrap() + 8;
+    let count = u32::from_be_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+    (0..count).map(|i| u32::from_be_bytes(data[at + 4 + i * 4..at + 8 + i * 4].try_into().unwrap())).collect()
+  }
+
+  #[test]
+  fn mp4_shifts_offsets_by_udta() {
+    let (file, before) = mp4_file(false);
+    let out = embed(file.clone().into(), "mp4", &metadata());
+    let delta = (out.len() - file.len()) as u32;
+    assert_eq!(offsets(&out), before.iter().map(|v| v + delta).collect::<Vec<_>>());
+    for offset in offsets(&out) {
+      assert_eq!(out[offset as usize], 7);
+    }
+    assert!(contains(&out, b"udta") && contains(&out, b"ilst") && contains(&out, b"\xa9ART"));
+  }
+
+  #[test]
+  fn mp4_keeps_offsets_before_moov() {
+    let (file, before) = mp4_file(true);
+    let out = embed(file.into(), "mp4", &metadata());
+    assert_eq!(offsets(&out), before);
+  }
+
+  #[test]
+  fn mp4_leaves_absolute_fragments() {
+    let mut tfhd = BytesMut::new();
+    tfhd.put_u32(1);
+    tfhd.put_u32(1);
+    tfhd.put_u64(0);
+    let moof = mp4_box(b"moof", &mp4_box(b"traf", &mp4_box(b"tfhd", &tfhd)));
+    let mut file = mp4_file(false).0;
+    file.extend_from_slice(&moof);
+    let out = embed(file.clone().into(), "mp4", &metadata());
+    assert_eq!(out, file);
+
+    let mut file = mp4_file(false).0;
+    file.extend_from_slice(&mp4_box(b"mfra", &mp4_box(b"tfra", &[0; 16])));
+    let out = embed(file.clone().into(), "mp4", &metadata());
+    assert_eq!(out, file);
+  }
+
+  #[test]
+  fn mp4_rejects_overflowing_large_size() {
+    let mut file = mp4_file(false).0;
+    file.extend_from_slice(&1u32.to_be_bytes());
+    file.extend_from_slice(b"free");
+    file.extend_from_slice(&u64::MAX.to_be_bytes());
+    assert!(boxes(&file, 0, file.len()).is_none());
+    let out = embed(file.clone().into(), "mp4", &metadata());
+    assert_eq!(out, file);
+  }
+}
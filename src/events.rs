@@ -0,0 +1,118 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use serde_json::to_string;
+
+use crate::manifest::Status;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Prints a message for people, on stdout in text output and on stderr in JSON output so it
+/// never mixes with the events.
+macro_rules! message {
+  ($($arg:tt)*) => {
+    if $crate::events::json() {
+      eprintln!($($arg)*);
+    } else {
+      println!($($arg)*);
+    }
+  };
+}
+
+/// Switches stdout to JSON lines of [`Event`], set once before anything is printed.
+pub fn set_json() {
+  JSON.store(true, Ordering::Relaxed);
+}
+
+pub fn json() -> bool {
+  JSON.load(Ordering::Relaxed)
+}
+
+/// What `--output json` prints, one object per line tagged by `event`, with a `time` in RFC 3339.
+/// Fields are only ever added, never renamed or removed.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+  /// A page of the likes of an account, with the number of media it held.
+  PageFetched { platform: &'a str, account: &'a str, items: usize },
+  /// A media found in a page or a post. `wanted` is false when it is filtered out or already
+  /// downloaded.
+  ItemDiscovered {
+    platform: &'a str,
+    account: &'a str,
+    filename: &'a str,
+    url: &'a str,
+    media_url: &'a str,
+    kind: &'a str,
+    wanted: bool,
+  },
+  DownloadStarted { platform: &'a str, account: &'a str, filename: &'a str, media_url: &'a str },
+  /// A media saved, linked to an identical file or skipped as one, see `status`.
+  DownloadFinished { platform: &'a str, account: &'a str, filename: &'a str, status: Status, bytes: usize },
+  DownloadFailed { platform: &'a str, account: &'a str, filename: &'a str, media_url: &'a str, error: &'a str },
+  /// A request answered with 429, retried after `wait_secs`.
+  RateLimited { url: &'a str, wait_secs: u64 },
+  AccountDone { platform: &'a str, account: &'a str, #[serde(flatten)] counts: &'a Counts, elapsed_secs: u64, stopped: bool },
+  /// The last event of a run, over every account.
+  RunSummary { accounts: usize, #[serde(flatten)] counts: &'a Counts, elapsed_secs: u64, stopped: bool },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+  #[serde(flatten)]
+  event: &'a Event<'a>,
+  time: String,
+}
+
+/// Prints `event` as a JSON line in JSON output, does nothing otherwise.
+pub fn emit(event: Event) {
+  if json() {
+    let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    println!("{}", to_string(&Line { event: &event, time }).unwrap());
+  }
+}
+
+/// Items of an account or a run by outcome.
+#[derive(Serialize, Default)]
+pub struct Counts {
+  discovered: AtomicU64,
+  downloaded: AtomicU64,
+  /// Filtered out or already downloaded.
+  skipped: AtomicU64,
+  /// Not saved because an identical file exists.
+  duplicate: AtomicU64,
+  failed: AtomicU64,
+}
+
+impl Counts {
+  pub fn discovered(&self, wanted: bool) {
+    self.discovered.fetch_add(1, Ordering::Relaxed);
+    if !wanted {
+      self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  pub fn finished(&self, status: Status) {
+    let count = match status {
+      Status::Downloaded | Status::Linked => &self.downloaded,
+      Status::Duplicate => &self.duplicate,
+      Status::Unsupported | Status::Failed => &self.failed,
+      Status::Removed | Status::Cancelled => return,
+    };
+    count.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Adds the counts of an account to those of the run.
+  pub fn add(&self, other: &Counts) {
+    for (a, b) in [
+      (&self.discovered, &other.discovered),
+      (&self.downloaded, &other.downloaded),
+      (&self.skipped, &other.skipped),
+      (&self.duplicate, &other.duplicate),
+      (&self.failed, &other.failed),
+    ] {
+      a.fetch_add(b.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+  }
+}
@@ -1,21 +1,46 @@
 #![feature(try_blocks)]
 
+#[macro_use]
+mod events;
 mod adapters;
+mod convert;
+mod dedup;
+mod download;
+mod dupes;
+mod embed;
+mod manifest;
+mod phash;
+mod plan;
+mod proxy;
+mod sanitize;
+mod shutdown;
+mod sidecar;
+mod template;
 
 use std::collections::LinkedList;
-use std::time::Duration;
-use std::{collections::HashSet, env, fs, io::Read, panic, path::Path, sync::Arc};
-use adapters::Adapters;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use reqwest::Proxy;
+use std::time::{Duration, Instant};
+use std::{env, fs, io::Read, panic, sync::Arc};
+use adapters::{parse_post_url, Adapters, Item, SharedAdapter};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use serde::{Deserialize, Serialize};
 use serde_json::{from_str, Value};
-use sysproxy::Sysproxy;
-use tokio::task::JoinHandle;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
 use tokio::time::sleep;
-use tokio::{fs::File, io::AsyncWriteExt};
 use adapters::twitter::TwitterAdapter;
 use adapters::bluesky::BlueSkyAdapter;
+use adapters::import::ImportAdapter;
+use adapters::bandwidth::BandwidthConfig;
+use adapters::http::{HttpConfig, HttpPool};
+use adapters::scheduler::{LimitsConfig, Scheduler};
+use dedup::{ContentStore, DedupConfig};
+use download::{Account, Shared};
+use events::{emit, Counts, Event};
+use plan::{Plan, Totals};
+use shutdown::Shutdown;
+
+/// Default of `queue_size`.
+const QUEUE_SIZE: usize = 256;
 
 #[inline(always)]
 fn pause() {
@@ -27,12 +52,119 @@ fn pause() {
 struct Config {
   accounts: Vec<Value>,
   proxy: Option<String>,
+  /// Hosts reached without the global proxy, in the format of `NO_PROXY`.
+  no_proxy: Option<String>,
+  ffmpeg: Option<String>,
+  dedup: Option<DedupConfig>,
+  /// Items of one account downloading or waiting for it, pagination pauses when it is reached.
+  queue_size: Option<usize>,
+  limits: Option<LimitsConfig>,
+  bandwidth: Option<BandwidthConfig>,
+  http: Option<HttpConfig>,
   pause_on_end: Option<bool>,
   pause_on_panic: Option<bool>,
 }
 
+enum Command {
+  /// Downloads the likes of every account.
+  Run,
+  /// Downloads single posts by URL, `tmd get <url>...`.
+  Get(Vec<String>),
+  /// Lists near identical images, `tmd dupes [--threshold <bits>] [--keep-best] [--dry-run]`.
+  Dupes { threshold: u32, keep_best: bool },
+}
+
+/// Turns `run` and `get` into a listing of what they would download, and keeps `dupes` from
+/// removing anything.
+struct DryRun {
+  /// Asks every new media for its size with a HEAD request.
+  sizes: bool,
+  /// Writes the listing as JSON lines to this file instead of printing it.
+  export: Option<String>,
+}
+
+struct Args {
+  command: Command,
+  dry_run: Option<DryRun>,
+  /// Prints JSON lines of [`Event`] instead of progress bars, `--output json`.
+  json: bool,
+}
+
+impl Args {
+  fn parse() -> Self {
+    let mut args = env::args().skip(1);
+    let (mut dry_run, mut sizes, mut export) = (false, false, None);
+    let (mut threshold, mut keep_best) = (dupes::THRESHOLD, false);
+    let mut json = false;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--dry-run" => dry_run = true,
+        "--sizes" => sizes = true,
+        "--export" => export = Some(args.next().expect("--export needs a file.")),
+        "--threshold" => threshold = args.next().and_then(|v| v.parse().ok()).expect("--threshold needs a number."),
+        "--keep-best" => keep_best = true,
+        "--output" => json = match args.next().as_deref() {
+          Some("json") => true,
+          Some("text") => false,
+          _ => panic!("--output needs \"text\" or \"json\"."),
+        },
+        option if option.starts_with("--") => panic!("Unknown option {option}."),
+        _ => positional.push(arg),
+      }
+    }
+    let mut positional = positional.into_iter();
+    let command = match positional.next().as_deref() {
+      Some("get") => Command::Get(positional.collect()),
+      Some("dupes") => Command::Dupes { threshold, keep_best },
+      Some(command) => panic!("Unknown command {command}, expected `get <url>...` or `dupes`."),
+      None => Command::Run,
+    };
+    if (sizes || export.is_some()) && !dry_run {
+      panic!("--sizes and --export only work with --dry-run.");
+    }
+    Self { command, dry_run: dry_run.then_some(DryRun { sizes, export }), json }
+  }
+}
+
+/// Counts `item` and reports it in JSON output.
+fn discovered(account: &Account, item: &dyn Item, wanted: bool, counts: &Counts) {
+  counts.discovered(wanted);
+  if events::json() {
+    emit(Event::ItemDiscovered {
+      platform: account.platform,
+      account: &account.name,
+      filename: &account.filename(item),
+      url: item.url(),
+      media_url: item.media_url(),
+      kind: item.kind().name(),
+      wanted,
+    });
+  }
+}
+
+fn is_import(v: &Value) -> bool {
+  v.get("platform").and_then(|v| v.as_str()) == Some("import")
+}
+
+/// The adapter of an account, imports resolve their urls through `resolvers`.
+fn adapter(v: &Value, http: &mut HttpPool, scheduler: &Arc<Scheduler>, resolvers: &[(&'static str, SharedAdapter)]) -> Box<dyn Adapters + Send> {
+  match v.get("platform").and_then(|v| v.as_str()) {
+    Some(_p @ "twitter" | _p @ "x") => Box::new(TwitterAdapter::new(v.clone(), &http.account(v), scheduler.clone())),
+    Some(_p @ "bluesky" | _p @ "bsky") => Box::new(BlueSkyAdapter::new(v.clone(), &http.account(v), scheduler.clone())),
+    Some(_p @ "import") => Box::new(ImportAdapter::new(v.clone(), resolvers.to_vec())),
+    Some(_) => panic!(),
+    None => panic!()
+  }
+}
+
 #[tokio::main]
 async fn main() {
+  let Args { command, dry_run, json } = Args::parse();
+  if json {
+    events::set_json();
+  }
+  let start = Instant::now();
   let raw = fs::read_to_string("./config.json").unwrap();
   let config = Arc::new(from_str::<Config>(raw.as_str()).unwrap());
   if config.pause_on_panic.unwrap_or(false) {
@@ -46,49 +178,122 @@ async fn main() {
     }));
   }
 
-  let mprogress = MultiProgress::new();
+  let mprogress = if json { MultiProgress::with_draw_target(ProgressDrawTarget::hidden()) } else { MultiProgress::new() };
   let style = ProgressStyle::with_template("[{prefix}] [{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
   .unwrap()
   .progress_chars("##-");
 
-  let proxy: Option<Proxy> = config.proxy.clone().or_else(|| {
-    let sysproxy = Sysproxy::get_system_proxy().ok()?;
-    if !sysproxy.enable {
-      return None;
-    }
-    Some(format!("http://{}:{}", sysproxy.host, sysproxy.port))
-  }).map(|s| Proxy::all(s).unwrap());
-
-  let accounts: Vec<_> = config.accounts.clone().into_iter().map(|v| {
-    let account = match v.get("platform").and_then(|v| v.as_str()) {
-      Some(_p @ "twitter" | _p @ "x") => Box::new(TwitterAdapter::new(v, proxy.clone())) as Box<dyn Adapters + Send>,
-      Some(_p @ "bluesky" | _p @ "bsky") => Box::new(BlueSkyAdapter::new(v, proxy.clone())),
-      Some(_) => panic!(),
-      None => panic!()
+  let proxy = proxy::resolve(config.proxy.as_deref(), config.no_proxy.as_deref(), |v| env::var(v).ok(), proxy::system);
+
+  let store = match (&config.dedup, &dry_run) {
+    (Some(dedup), None) => Some(Arc::new(ContentStore::open(dedup).await.unwrap())),
+    _ => None,
+  };
+  let mut http = HttpPool::new(config.http.clone().unwrap_or_default(), proxy);
+  let scheduler = Arc::new(Scheduler::new(config.limits.clone().unwrap_or_default(), config.bandwidth.as_ref()));
+  let shutdown = Shutdown::listen();
+  let shared = Shared {
+    ffmpeg: config.ffmpeg.clone().unwrap_or("ffmpeg".to_owned()),
+    store: store.clone(),
+    shutdown: shutdown.clone(),
+    dry_run: dry_run.is_some(),
+  };
+  let plan = match &dry_run {
+    Some(dry_run) => Some(Arc::new(Plan::new(dry_run.sizes, dry_run.export.as_deref()).await)),
+    None => None,
+  };
+  // Imports share the adapters of the other accounts, so those are created first.
+  let mut created = Vec::new();
+  for v in &config.accounts {
+    created.push((!is_import(v)).then(|| adapter(v, &mut http, &scheduler, &[])).map(|a| (a.platform(), Arc::new(Mutex::new(a)))));
+  }
+  let resolvers: Vec<_> = created.iter().flatten().cloned().collect();
+  let mut accounts = Vec::new();
+  for (v, created) in config.accounts.iter().zip(created) {
+    let adapter: SharedAdapter = match created {
+      Some((_, adapter)) => adapter,
+      None => Arc::new(Mutex::new(adapter(v, &mut http, &scheduler, &resolvers))),
     };
+    let account = Arc::new(Account::open(&**adapter.lock().await, v, &shared).await);
+    accounts.push((adapter, account));
+  }
 
-    let mut set = HashSet::<String>::new();
-    if let Ok(paths) = std::fs::read_dir(account.path()) {
-      for entry in paths {
-        let path = entry.unwrap().path();
-        set.insert(path.file_name().unwrap().to_str().unwrap().to_string());
+  if let Command::Dupes { threshold, keep_best } = command {
+    let accounts: Vec<_> = accounts.into_iter().map(|(_, account)| account).collect();
+    dupes::run(&accounts, threshold, keep_best, dry_run.is_some()).await;
+    if config.pause_on_end.unwrap_or(false) {
+      pause();
+    }
+    return;
+  }
+
+  if let Command::Get(urls) = command {
+    let counts = Counts::default();
+    for url in urls {
+      if shutdown.is_requested() {
+        break;
       }
+      let Some((platform, id)) = parse_post_url(&url) else {
+        message!("Unknown post url {url}, skipped.");
+        continue;
+      };
+      let Some((adapter, account)) = accounts.iter().find(|(_, account)| account.platform == platform) else {
+        message!("No {platform} account configured for {url}, skipped.");
+        continue;
+      };
+      let totals = Totals::default();
+      let items = adapter.lock().await.post(&id).await;
+      for item in items {
+        let wanted = match plan {
+          Some(_) => account.accepts(&*item),
+          None => account.wants(&*item),
+        };
+        discovered(account, &*item, wanted, &counts);
+        match &plan {
+          Some(plan) if wanted => plan.add(account, &*item, &totals).await,
+          None if wanted => counts.finished(account.download(&*item).await),
+          _ => {}
+        }
+      }
+      match &plan {
+        Some(plan) => message!("[{} {}] {url} {}", account.platform, account.name, plan.summary(&totals)),
+        None => message!("[{} {}] {url} Done!", account.platform, account.name),
+      }
+    }
+    for (_, account) in &accounts {
+      account.flush().await;
+    }
+    if let Some(plan) = &plan {
+      plan.flush().await;
     }
-    let set = Arc::new(set);
-    let _ = fs::create_dir(account.path());
-    (account, set)
-  }).collect();
+    if let Some(store) = &store {
+      store.flush().await;
+    }
+    emit(Event::RunSummary {
+      accounts: accounts.len(),
+      counts: &counts,
+      elapsed_secs: start.elapsed().as_secs(),
+      stopped: shutdown.is_requested(),
+    });
+    if config.pause_on_end.unwrap_or(false) && !shutdown.is_requested() {
+      pause();
+    }
+    return;
+  }
 
-  let mut handles = LinkedList::<JoinHandle<()>>::new();
+  let mut handles = LinkedList::<JoinHandle<Arc<Counts>>>::new();
+  let account_count = accounts.len();
+  let queue_size = config.queue_size.unwrap_or(QUEUE_SIZE).max(1);
 
-  for (mut account, set) in accounts.into_iter() {
-    let set = set.clone();
+  for (adapter, account) in accounts.into_iter() {
     let mprogress = mprogress.clone();
     let style = style.clone();
+    let shutdown = shutdown.clone();
+    let plan = plan.clone();
     handles.push_back(tokio::spawn(async move {
       let pb = mprogress.add(ProgressBar::new(0));
       pb.set_style(style.clone());
-      pb.set_prefix(format!("{} {}", account.platform(), account.name()));
+      pb.set_prefix(format!("{} {}", account.platform, account.name));
       let tick = pb.clone();
       let ticker = tokio::spawn(async move {
         loop {
@@ -96,50 +301,92 @@ async fn main() {
           tick.tick();
         }
       });
-      let mut handles = LinkedList::<JoinHandle<()>>::new();
+      let queue = Arc::new(Semaphore::new(queue_size));
+      let mut tasks = JoinSet::new();
+      let totals = Arc::new(Totals::default());
+      let counts = Arc::new(Counts::default());
 
-      while let Some(item) = account.next().await {
+      loop {
+        let item = tokio::select! {
+          item = async { adapter.lock().await.next().await } => item,
+          _ = shutdown.requested() => None,
+        };
+        let Some(item) = item else {
+          break;
+        };
         pb.inc_length(1);
-        if set.contains(item.filename()) {
+        let wanted = match plan {
+          Some(_) => account.accepts(&*item),
+          None => account.wants(&*item),
+        };
+        discovered(&account, &*item, wanted, &counts);
+        if !wanted {
           pb.inc(1);
           continue;
         }
+        let permit = queue.clone().acquire_owned().await.unwrap();
+        while let Some(result) = tasks.try_join_next() {
+          result.unwrap();
+        }
         let pb = pb.clone();
-        let dir = account.path().to_owned();
-        handles.push_back(tokio::spawn(async move {
+        let account = account.clone();
+        let plan = plan.clone();
+        let totals = totals.clone();
+        let counts = counts.clone();
+        tasks.spawn(async move {
           pb.set_message(item.url().to_owned());
-          let path = Path::new(&dir).join(item.filename());
-          let bytes = &mut item.get().await;
-          let result = File::create(&path).await;
-          if result.is_err() {
-            println!("Cannot create file {}, url {}, skipped.", item.filename(), item.media_url());
-          } else {
-            let mut file = result.unwrap();
-            let result = file.write_all_buf(bytes).await;
-            if result.is_err() {
-              println!("IO error in {}, url {}.", item.filename(), item.media_url());
-            }
+          match plan {
+            Some(plan) => plan.add(&account, &*item, &totals).await,
+            None => counts.finished(account.download(&*item).await),
           }
           pb.inc(1);
-        }));
+          drop(permit);
+        });
       }
-      
-      for handle in handles {
-        handle.await.unwrap();
-      };
+
+      while let Some(result) = tasks.join_next().await {
+        result.unwrap();
+      }
+      account.flush().await;
       ticker.abort();
       let secs = pb.elapsed().as_secs();
       let h = secs / 3600;
       let m = (secs % 3600) / 60;
       let s = secs % 60;
-      mprogress.println(format!("[{} {}] [{h:02}:{m:02}:{s:02}] all tasks Done!", account.platform(), account.name())).unwrap();
+      let done = match &plan {
+        Some(plan) => plan.summary(&totals),
+        None if shutdown.is_requested() => "stopped, unfinished items are downloaded next run.".to_owned(),
+        None => "all tasks Done!".to_owned(),
+      };
+      mprogress.println(format!("[{} {}] [{h:02}:{m:02}:{s:02}] {done}", account.platform, account.name)).unwrap();
+      emit(Event::AccountDone {
+        platform: account.platform,
+        account: &account.name,
+        counts: &counts,
+        elapsed_secs: secs,
+        stopped: shutdown.is_requested(),
+      });
+      counts
     }));
   }
   
+  let counts = Counts::default();
   for handle in handles {
-    handle.await.unwrap();
+    counts.add(&handle.await.unwrap());
+  }
+  if let Some(plan) = &plan {
+    plan.flush().await;
+  }
+  if let Some(store) = &store {
+    store.flush().await;
   }
-  if config.pause_on_end.unwrap_or(false) {
+  emit(Event::RunSummary {
+    accounts: account_count,
+    counts: &counts,
+    elapsed_secs: start.elapsed().as_secs(),
+    stopped: shutdown.is_requested(),
+  });
+  if config.pause_on_end.unwrap_or(false) && !shutdown.is_requested() {
     pause();
   }
 }
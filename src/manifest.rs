@@ -0,0 +1,174 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
+
+use crate::adapters::{Item, MediaKind};
+
+pub const MANIFEST: &str = ".manifest.jsonl";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+  Downloaded,
+  /// Linked to a file with the same content, see `duplicate_of`.
+  Linked,
+  /// Not saved, a file with the same content exists at `duplicate_of`.
+  Duplicate,
+  Unsupported,
+  Failed,
+  /// Deleted by `tmd dupes` in favour of a better copy, and not downloaded again.
+  Removed,
+  /// Stopped by a shutdown, never recorded so the next run downloads it.
+  Cancelled,
+}
+
+#[derive(Serialize)]
+pub struct Record<'a> {
+  pub platform: &'a str,
+  pub account: &'a str,
+  pub filename: &'a str,
+  pub url: &'a str,
+  pub media_url: &'a str,
+  pub kind: &'a str,
+  /// The platform's own name for a media type tmd does not know.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub media_type: Option<&'a str>,
+  pub status: Status,
+  /// BLAKE3 hash of the content, when deduplication is enabled.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub hash: Option<&'a str>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub duplicate_of: Option<&'a str>,
+  /// Perceptual hash of images as 16 hex digits, when enabled.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub phash: Option<&'a str>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub width: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub height: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub alt_text: Option<&'a str>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub post_text: Option<&'a str>,
+}
+
+impl<'a> Record<'a> {
+  pub fn new(platform: &'a str, account: &'a str, item: &'a dyn Item, filename: &'a str, status: Status) -> Self {
+    let media_type = match item.kind() {
+      MediaKind::Unknown(media_type) => Some(media_type.as_str()),
+      _ => None,
+    };
+    Self {
+      platform,
+      account,
+      filename,
+      url: item.url(),
+      media_url: item.media_url(),
+      kind: item.kind().name(),
+      media_type,
+      status,
+      hash: None,
+      duplicate_of: None,
+      phash: None,
+      width: None,
+      height: None,
+      alt_text: None,
+      post_text: None,
+    }
+  }
+
+  /// Marks the file of `entry` as removed.
+  pub fn removed(entry: &'a Entry) -> Self {
+    Self {
+      platform: &entry.platform,
+      account: &entry.account,
+      filename: &entry.filename,
+      url: &entry.url,
+      media_url: &entry.media_url,
+      kind: &entry.kind,
+      media_type: None,
+      status: Status::Removed,
+      hash: None,
+      duplicate_of: None,
+      phash: entry.phash.as_deref(),
+      width: entry.width,
+      height: entry.height,
+      alt_text: None,
+      post_text: None,
+    }
+  }
+
+  /// Includes the alt text and post text of `item`, for search tools reading the manifest.
+  pub fn with_text(mut self, item: &'a dyn Item) -> Self {
+    self.alt_text = item.alt_text();
+    self.post_text = Some(item.post_text()).filter(|v| !v.is_empty());
+    self
+  }
+}
+
+/// A record read back from a manifest, with the fields tmd itself needs.
+#[derive(Deserialize)]
+pub struct Entry {
+  pub platform: String,
+  pub account: String,
+  pub filename: String,
+  pub url: String,
+  pub media_url: String,
+  pub kind: String,
+  pub status: Status,
+  pub duplicate_of: Option<String>,
+  pub phash: Option<String>,
+  pub width: Option<u32>,
+  pub height: Option<u32>,
+}
+
+/// Every record in the manifest under `dir`, skipping lines it cannot read.
+pub fn read(dir: &str) -> Vec<Entry> {
+  std::fs::read_to_string(Path::new(dir).join(MANIFEST))
+    .map(|raw| raw.lines().filter_map(|line| from_str(line).ok()).collect())
+    .unwrap_or_default()
+}
+
+/// Append-only JSON lines log of every item handled, kept in the account directory.
+pub struct Manifest {
+  /// `None` when records are dropped, in a dry run.
+  file: Option<Mutex<BufWriter<File>>>,
+}
+
+impl Manifest {
+  pub async fn open(dir: &str) -> std::io::Result<Self> {
+    let file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(Path::new(dir).join(MANIFEST))
+      .await?;
+    Ok(Self { file: Some(Mutex::new(BufWriter::new(file))) })
+  }
+
+  pub fn disabled() -> Self {
+    Self { file: None }
+  }
+
+  pub async fn write(&self, record: &Record<'_>) {
+    let Some(file) = &self.file else {
+      return;
+    };
+    let line = to_string(record).unwrap() + "\n";
+    if file.lock().await.write_all(line.as_bytes()).await.is_err() {
+      message!("IO error in manifest, record of {} lost.", record.filename);
+    }
+  }
+
+  pub async fn flush(&self) {
+    let Some(file) = &self.file else {
+      return;
+    };
+    if file.lock().await.flush().await.is_err() {
+      message!("IO error in manifest, records may be lost.");
+    }
+  }
+}
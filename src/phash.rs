@@ -0,0 +1,21 @@
+use image::imageops::FilterType;
+
+/// Difference hash of an image and its size, `None` when `bytes` is not a known image.
+///
+/// The image is shrunk to 9x8 in grayscale and every bit tells whether a pixel is darker than
+/// its right neighbour, so re-encoded or resized copies end up a few bits apart at most.
+pub fn dhash(bytes: &[u8]) -> Option<(u64, u32, u32)> {
+  let image = image::load_from_memory(bytes).ok()?;
+  let small = image.grayscale().resize_exact(9, 8, FilterType::Triangle).into_luma8();
+  let mut hash = 0u64;
+  for y in 0..8 {
+    for x in 0..8 {
+      hash = (hash << 1) | (small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0]) as u64;
+    }
+  }
+  Some((hash, image.width(), image.height()))
+}
+
+pub fn distance(a: u64, b: u64) -> u32 {
+  (a ^ b).count_ones()
+}
@@ -0,0 +1,109 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+use serde_json::to_string;
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
+
+use crate::adapters::Item;
+use crate::download::Account;
+
+#[derive(Serialize)]
+struct Planned<'a> {
+  platform: &'a str,
+  account: &'a str,
+  filename: &'a str,
+  url: &'a str,
+  media_url: &'a str,
+  kind: &'a str,
+  /// False when the file is already in the account path.
+  new: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  size: Option<u64>,
+}
+
+/// Lists what a run would download, printed or exported as JSON lines.
+pub struct Plan {
+  sizes: bool,
+  export: Option<Mutex<BufWriter<File>>>,
+}
+
+/// Counts of one account, for the summary line.
+#[derive(Default)]
+pub struct Totals {
+  new: AtomicU64,
+  existing: AtomicU64,
+  bytes: AtomicU64,
+}
+
+impl Plan {
+  pub async fn new(sizes: bool, export: Option<&str>) -> Self {
+    let export = match export {
+      Some(path) => Some(Mutex::new(BufWriter::new(File::create(path).await.unwrap()))),
+      None => None,
+    };
+    Self { sizes, export }
+  }
+
+  /// Lists `item` of `account`, with its size when sizes are asked for and it is new.
+  pub async fn add(&self, account: &Account, item: &dyn Item, totals: &Totals) {
+    let new = !account.exists(item);
+    let size = if self.sizes && new { item.size().await } else { None };
+    let filename = account.filename(item);
+    let planned = Planned {
+      platform: account.platform,
+      account: &account.name,
+      filename: &filename,
+      url: item.url(),
+      media_url: item.media_url(),
+      kind: item.kind().name(),
+      new,
+      size,
+    };
+
+    if new {
+      totals.new.fetch_add(1, Ordering::Relaxed);
+      totals.bytes.fetch_add(size.unwrap_or(0), Ordering::Relaxed);
+    } else {
+      totals.existing.fetch_add(1, Ordering::Relaxed);
+    }
+    match &self.export {
+      Some(export) => {
+        let line = to_string(&planned).unwrap() + "\n";
+        if export.lock().await.write_all(line.as_bytes()).await.is_err() {
+          message!("IO error in export, {} not listed.", filename);
+        }
+      }
+      None => {
+        let size = size.map(|v| format!(" {}", bytes(v))).unwrap_or_default();
+        let mark = if new { "new" } else { "existing" };
+        message!("[{mark}] {filename}{size} {} {}", planned.url, planned.media_url);
+      }
+    }
+  }
+
+  pub fn summary(&self, totals: &Totals) -> String {
+    let new = totals.new.load(Ordering::Relaxed);
+    let existing = totals.existing.load(Ordering::Relaxed);
+    let size = if self.sizes { format!(" ({})", bytes(totals.bytes.load(Ordering::Relaxed))) } else { String::new() };
+    format!("{new} new items{size}, {existing} already downloaded.")
+  }
+
+  pub async fn flush(&self) {
+    if let Some(export) = &self.export {
+      if export.lock().await.flush().await.is_err() {
+        message!("IO error in export, lines may be lost.");
+      }
+    }
+  }
+}
+
+fn bytes(n: u64) -> String {
+  match n {
+    n if n >= 1 << 30 => format!("{:.1} GiB", n as f64 / (1u64 << 30) as f64),
+    n if n >= 1 << 20 => format!("{:.1} MiB", n as f64 / (1u64 << 20) as f64),
+    n if n >= 1 << 10 => format!("{:.1} KiB", n as f64 / (1u64 << 10) as f64),
+    n => format!("{n} B"),
+  }
+}
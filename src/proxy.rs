@@ -0,0 +1,200 @@
+use sysproxy::Sysproxy;
+
+/// The proxy of accounts without their own, and the hosts reached without it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolved {
+  pub url: String,
+  /// Comma separated hosts, domains and CIDR ranges, as in `NO_PROXY`.
+  pub no_proxy: Option<String>,
+}
+
+/// A proxy from the system settings, with the scheme it was configured as.
+pub struct SystemProxy {
+  pub scheme: &'static str,
+  pub host: String,
+  pub port: u16,
+  pub bypass: String,
+}
+
+/// Variables checked in order. tmd only fetches https urls, and `HTTP_PROXY` only applies to
+/// http ones, as in curl.
+const PROXY_VARS: [&str; 4] = ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"];
+
+/// Finds the proxy in `proxy` from the config, then the environment, then the system settings.
+///
+/// Urls keep their scheme, so `socks5://` stays a SOCKS proxy, and `host:port` alone means
+/// `http://`. The environment and the system are read through `env` and `system`, so any source
+/// can be replaced, e.g. by a local stand-in proxy.
+pub fn resolve(
+  proxy: Option<&str>,
+  no_proxy: Option<&str>,
+  env: impl Fn(&str) -> Option<String>,
+  system: impl FnOnce() -> Option<SystemProxy>,
+) -> Option<Resolved> {
+  let with_scheme = |url: &str| if url.contains("://") { url.to_owned() } else { format!("http://{url}") };
+  if let Some(url) = proxy.filter(|v| !v.is_empty()) {
+    return Some(Resolved { url: with_scheme(url), no_proxy: no_proxy.map(str::to_owned) });
+  }
+
+  let var = |names: &[&str]| names.iter().find_map(|name| env(name).filter(|v| !v.is_empty()));
+  if let Some(url) = var(&PROXY_VARS) {
+    let no_proxy = no_proxy.map(str::to_owned).or_else(|| var(&["NO_PROXY", "no_proxy"]));
+    return Some(Resolved { url: with_scheme(&url), no_proxy });
+  }
+
+  let system = system()?;
+  let bypass: Vec<_> = system.bypass
+    .split([',', ';'])
+    .map(|v| v.trim().trim_matches(['[', ']', '\'', '"', ' ']))
+    .filter(|v| !v.is_empty() && *v != "<local>")
+    .collect();
+  Some(Resolved {
+    url: format!("{}://{}:{}", system.scheme, system.host, system.port),
+    no_proxy: no_proxy.map(str::to_owned).or_else(|| (!bypass.is_empty()).then(|| bypass.join(","))),
+  })
+}
+
+/// The enabled system proxy, SOCKS where the platform tells it apart.
+pub fn system() -> Option<SystemProxy> {
+  let proxy = Sysproxy::get_system_proxy().ok().filter(|v| v.enable && !v.host.is_empty())?;
+  Some(SystemProxy {
+    scheme: if is_socks(&proxy) { "socks5" } else { "http" },
+    host: proxy.host,
+    port: proxy.port,
+    bypass: proxy.bypass,
+  })
+}
+
+#[cfg(target_os = "linux")]
+fn is_socks(proxy: &Sysproxy) -> bool {
+  Sysproxy::get_socks().is_ok_and(|socks| socks.host == proxy.host && socks.port == proxy.port)
+}
+
+#[cfg(target_os = "macos")]
+fn is_socks(proxy: &Sysproxy) -> bool {
+  let Ok(output) = std::process::Command::new("scutil").arg("--proxy").output() else {
+    return false;
+  };
+  let output = String::from_utf8_lossy(&output.stdout);
+  let value = |key: &str| output.lines()
+    .find_map(|line| line.trim().strip_prefix(key)?.trim_start().strip_prefix(':').map(str::trim));
+  value("SOCKSEnable") == Some("1")
+    && value("SOCKSProxy") == Some(proxy.host.as_str())
+    && value("SOCKSPort") == Some(proxy.port.to_string().as_str())
+}
+
+/// Windows keeps SOCKS proxies as `socks=host:port`, which is not read as a system proxy at all.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn is_socks(_proxy: &Sysproxy) -> bool {
+  false
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio::net::TcpListener;
+
+  use super::*;
+  use crate::adapters::http::{Http, HttpConfig, Rotation};
+
+  fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars: HashMap<_, _> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    move |name| vars.get(name).cloned()
+  }
+
+  fn system(bypass: &str) -> impl FnOnce() -> Option<SystemProxy> {
+    let bypass = bypass.to_owned();
+    move || Some(SystemProxy { scheme: "socks5", host: "10.0.0.3".to_owned(), port: 1080, bypass })
+  }
+
+  fn url(resolved: Option<Resolved>) -> Option<String> {
+    resolved.map(|v| v.url)
+  }
+
+  #[test]
+  fn config_over_env_over_system() {
+    let vars = env(&[("HTTPS_PROXY", "http://10.0.0.2:8080")]);
+    assert_eq!(url(resolve(Some("http://10.0.0.1:8080"), None, &vars, system(""))), Some("http://10.0.0.1:8080".into()));
+    assert_eq!(url(resolve(None, None, &vars, system(""))), Some("http://10.0.0.2:8080".into()));
+    assert_eq!(url(resolve(Some(""), None, env(&[]), system(""))), Some("socks5://10.0.0.3:1080".into()));
+    assert_eq!(resolve(None, None, env(&[]), || None), None);
+  }
+
+  #[test]
+  fn env_order_skips_http_proxy() {
+    let vars = env(&[("HTTP_PROXY", "http://10.0.0.9:80"), ("all_proxy", "http://10.0.0.4:80")]);
+    assert_eq!(url(resolve(None, None, &vars, || None)), Some("http://10.0.0.4:80".into()));
+    assert_eq!(resolve(None, None, env(&[("HTTP_PROXY", "http://10.0.0.9:80")]), || None), None);
+  }
+
+  #[test]
+  fn schemes() {
+    assert_eq!(url(resolve(Some("socks5://127.0.0.1:1080"), None, env(&[]), || None)), Some("socks5://127.0.0.1:1080".into()));
+    assert_eq!(url(resolve(Some("127.0.0.1:8080"), None, env(&[]), || None)), Some("http://127.0.0.1:8080".into()));
+    assert_eq!(url(resolve(None, None, env(&[("ALL_PROXY", "127.0.0.1:3128")]), || None)), Some("http://127.0.0.1:3128".into()));
+  }
+
+  #[test]
+  fn no_proxy_sources() {
+    let vars = env(&[("HTTPS_PROXY", "http://10.0.0.2:8080"), ("NO_PROXY", "localhost")]);
+    assert_eq!(resolve(None, None, &vars, || None).unwrap().no_proxy.as_deref(), Some("localhost"));
+    assert_eq!(resolve(None, Some(".example.com"), &vars, || None).unwrap().no_proxy.as_deref(), Some(".example.com"));
+    assert_eq!(resolve(Some("http://10.0.0.1:8080"), None, &vars, || None).unwrap().no_proxy, None);
+  }
+
+  #[test]
+  fn windows_bypass_list() {
+    let resolved = resolve(None, None, env(&[]), system("localhost;*.example.com; <local>;[10.0.0.0/8]")).unwrap();
+    assert_eq!(resolved.no_proxy.as_deref(), Some("localhost,*.example.com,10.0.0.0/8"));
+    assert_eq!(resolve(None, None, env(&[]), system("<local>")).unwrap().no_proxy, None);
+    let resolved = resolve(None, Some("example.org"), env(&[]), system("localhost")).unwrap();
+    assert_eq!(resolved.no_proxy.as_deref(), Some("example.org"));
+  }
+
+  /// Accepts one connection, answers it with `response` and returns the request line.
+  async fn stand_in(response: &'static str) -> (u16, tokio::task::JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut request = Vec::new();
+      let mut buf = [0; 1024];
+      while !request.ends_with(b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await.unwrap();
+        if n == 0 {
+          break;
+        }
+        request.extend_from_slice(&buf[..n]);
+      }
+      stream.write_all(response.as_bytes()).await.unwrap();
+      String::from_utf8_lossy(&request).lines().next().unwrap_or_default().to_owned()
+    });
+    (port, handle)
+  }
+
+  #[tokio::test]
+  async fn requests_go_through_the_resolved_proxy() {
+    let (port, proxy) = stand_in("HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok").await;
+    let resolved = resolve(None, None, env(&[("HTTPS_PROXY", &format!("127.0.0.1:{port}"))]), || None).unwrap();
+    let http = Http::new(&HttpConfig::default(), &[resolved.url], Rotation::default(), None);
+    let body = http.get("http://tmd.invalid/file").send().await.unwrap().text().await.unwrap();
+    assert_eq!(body, "ok");
+    assert_eq!(proxy.await.unwrap(), "GET http://tmd.invalid/file HTTP/1.1");
+
+    let (port, proxy) = stand_in("HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\n\r\n").await;
+    let http = Http::new(&HttpConfig::default(), &[format!("http://127.0.0.1:{port}")], Rotation::default(), None);
+    assert!(http.get("https://tmd.invalid/file").send().await.is_err());
+    assert_eq!(proxy.await.unwrap(), "CONNECT tmd.invalid:443 HTTP/1.1");
+  }
+
+  #[tokio::test]
+  async fn bypassed_hosts_are_reached_directly() {
+    let (port, origin) = stand_in("HTTP/1.1 200 OK\r\ncontent-length: 6\r\n\r\ndirect").await;
+    let http = Http::new(&HttpConfig::default(), &["http://127.0.0.1:9".to_owned()], Rotation::default(), Some("127.0.0.1"));
+    let body = http.get(format!("http://127.0.0.1:{port}/file")).send().await.unwrap().text().await.unwrap();
+    assert_eq!(body, "direct");
+    assert_eq!(origin.await.unwrap(), "GET /file HTTP/1.1");
+  }
+}
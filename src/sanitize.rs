@@ -0,0 +1,137 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use unicode_normalization::UnicodeNormalization;
+
+/// The common limit of ext4, NTFS, APFS and most other filesystems.
+pub const MAX_BYTES: usize = 255;
+
+/// Room kept in file names for what tmd appends to them later, the longest being a `.json`
+/// sidecar of a gif converted to `webp`, one byte longer than `mp4`. `.part` and `.txt` fit too.
+const SUFFIX_BYTES: usize = 6;
+
+const RESERVED: [&str; 22] = [
+  "CON", "PRN", "AUX", "NUL",
+  "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+  "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turns rendered paths into ones that can be created on Windows, macOS and Linux alike.
+///
+/// Paths are normalised to NFC, every component loses the characters Windows rejects, trailing
+/// dots and spaces and reserved device names, and is cut to `max_bytes`, file names to
+/// [`SUFFIX_BYTES`] less. Components changed or
+/// cut this way end with a hash of the original, so they never take the name of a path that
+/// needed no change, and get the same name on every run. A path that only differs in case from
+/// one already on disk ends with the hash as well.
+pub struct Sanitizer {
+  max_bytes: usize,
+  /// Sanitised paths in lower case, mapped to the path itself.
+  claimed: Mutex<HashMap<String, String>>,
+}
+
+impl Sanitizer {
+  /// `existing` are the paths already in the account path, which keep their names.
+  pub fn new(max_bytes: usize, existing: &HashSet<String>) -> Self {
+    let claimed = existing.iter().map(|v| (v.to_lowercase(), v.clone())).collect();
+    Self { max_bytes, claimed: Mutex::new(claimed) }
+  }
+
+  pub fn sanitize(&self, path: &str) -> String {
+    let path: String = path.nfc().collect();
+    let originals: Vec<_> = path.split('/').collect();
+    let last = originals.len() - 1;
+    let max_bytes = |i| if i == last { self.max_bytes.saturating_sub(SUFFIX_BYTES) } else { self.max_bytes };
+    let mut components: Vec<_> = originals.iter().enumerate().map(|(i, v)| component(v, false, max_bytes(i))).collect();
+    let mut sanitized = components.join("/");
+
+    let mut claimed = self.claimed.lock().unwrap();
+    if claimed.get(&sanitized.to_lowercase()).is_some_and(|other| *other != sanitized) {
+      *components.last_mut().unwrap() = component(originals[last], true, max_bytes(last));
+      sanitized = components.join("/");
+    }
+    claimed.insert(sanitized.to_lowercase(), sanitized.clone());
+    sanitized
+  }
+}
+
+/// Normalises already existing names, so they compare equal to sanitised ones.
+pub fn normalize(name: &str) -> String {
+  name.nfc().collect()
+}
+
+/// Sanitises one path component, ending it with a hash of `name` when it had to change or
+/// `tagged` is set.
+fn component(name: &str, tagged: bool, max_bytes: usize) -> String {
+  let mut clean: String = name.chars()
+    .map(|c| if c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*') { '_' } else { c })
+    .collect();
+  clean = clean.trim_start().trim_end_matches([' ', '.']).to_owned();
+  if clean.is_empty() {
+    clean = "_".to_owned();
+  }
+  let stem = clean.split('.').next().unwrap_or_default();
+  if RESERVED.iter().any(|v| v.eq_ignore_ascii_case(stem)) {
+    clean.insert(stem.len(), '_');
+  }
+
+  let hash = format!("~{:08x}", fnv1a(name.as_bytes()));
+  let (stem, ext) = match clean.rsplit_once('.') {
+    Some((stem, ext)) if !stem.is_empty() && ext.len() <= 16 => (stem, format!(".{ext}")),
+    _ => (clean.as_str(), String::new()),
+  };
+  let tag = if tagged || clean != name { hash.as_str() } else { "" };
+  if stem.len() + tag.len() + ext.len() <= max_bytes {
+    return format!("{stem}{tag}{ext}");
+  }
+  let mut end = max_bytes.saturating_sub(hash.len() + ext.len()).min(stem.len());
+  while !stem.is_char_boundary(end) {
+    end -= 1;
+  }
+  format!("{}{hash}{ext}", &stem[..end])
+}
+
+/// A hash that stays the same across runs and Rust versions.
+fn fnv1a(bytes: &[u8]) -> u32 {
+  bytes.iter().fold(0x811c9dc5, |hash, b| (hash ^ *b as u32).wrapping_mul(0x01000193))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn changed_names_never_take_unchanged_ones() {
+    let sanitizer = Sanitizer::new(MAX_BYTES, &HashSet::new());
+    let changed = sanitizer.sanitize("x:y?.jpg");
+    assert_ne!(changed, "x_y_.jpg");
+    assert!(changed.starts_with("x_y_~") && changed.ends_with(".jpg"));
+    assert_eq!(sanitizer.sanitize("x_y_.jpg"), "x_y_.jpg");
+
+    let next_run = Sanitizer::new(MAX_BYTES, &HashSet::from(["x_y_.jpg".to_owned(), changed.clone()]));
+    assert_eq!(next_run.sanitize("x_y_.jpg"), "x_y_.jpg");
+    assert_eq!(next_run.sanitize("x:y?.jpg"), changed);
+  }
+
+  #[test]
+  fn case_collisions_keep_the_existing_name() {
+    let sanitizer = Sanitizer::new(MAX_BYTES, &HashSet::from(["a/b.jpg".to_owned()]));
+    assert_eq!(sanitizer.sanitize("a/b.jpg"), "a/b.jpg");
+    let other = sanitizer.sanitize("a/B.jpg");
+    assert!(other.starts_with("a/B~") && other.ends_with(".jpg"));
+    assert_eq!(Sanitizer::new(MAX_BYTES, &HashSet::new()).sanitize("a/B.jpg"), "a/B.jpg");
+  }
+
+  #[test]
+  fn reserved_and_long_names() {
+    let sanitizer = Sanitizer::new(MAX_BYTES, &HashSet::new());
+    assert!(sanitizer.sanitize("CON.jpg").starts_with("CON_~"));
+    assert!(sanitizer.sanitize("name. ").starts_with("name~"));
+    let long = sanitizer.sanitize(&format!("{}.jpg", "é".repeat(200)));
+    assert!(long.len() + ".webp.json".len() - ".jpg".len() <= MAX_BYTES && long.ends_with(".jpg") && long.contains('~'));
+    assert!(format!("{long}.part").len() <= MAX_BYTES);
+    let dir = sanitizer.sanitize(&format!("{}/a.jpg", "d".repeat(MAX_BYTES)));
+    assert_eq!(dir, format!("{}/a.jpg", "d".repeat(MAX_BYTES)));
+    assert_ne!(long, sanitizer.sanitize(&format!("{}x.jpg", "é".repeat(200))));
+  }
+}
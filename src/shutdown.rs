@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
+/// Set on the first Ctrl-C or SIGTERM, the second one exits at once.
+#[derive(Default)]
+pub struct Shutdown {
+  requested: AtomicBool,
+  notify: Notify,
+}
+
+impl Shutdown {
+  /// Starts listening for the signals.
+  pub fn listen() -> Arc<Self> {
+    let shutdown = Arc::new(Self::default());
+    let listener = shutdown.clone();
+    tokio::spawn(async move {
+      signal().await;
+      message!("Stopping, unfinished downloads are dropped. Press Ctrl-C again to exit now.");
+      listener.requested.store(true, Ordering::SeqCst);
+      listener.notify.notify_waiters();
+      signal().await;
+      std::process::exit(130);
+    });
+    shutdown
+  }
+
+  pub fn is_requested(&self) -> bool {
+    self.requested.load(Ordering::SeqCst)
+  }
+
+  /// Resolves once a shutdown is requested.
+  pub async fn requested(&self) {
+    let notified = self.notify.notified();
+    if self.is_requested() {
+      return;
+    }
+    notified.await;
+  }
+}
+
+#[cfg(unix)]
+async fn signal() {
+  use tokio::signal::unix::{signal, SignalKind};
+  let mut terminate = signal(SignalKind::terminate()).unwrap();
+  tokio::select! {
+    _ = tokio::signal::ctrl_c() => {}
+    _ = terminate.recv() => {}
+  }
+}
+
+#[cfg(not(unix))]
+async fn signal() {
+  let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,89 @@
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{to_string_pretty, Value};
+
+use crate::adapters::{Item, PostMedia, Stats};
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Per {
+  /// `<file>.json` next to every downloaded file.
+  #[default]
+  Item,
+  /// `<author> <id>.json` once per post, in the directory of its first downloaded file.
+  Post,
+}
+
+/// Writes the post behind each download as JSON, in the same schema for every platform.
+#[derive(Deserialize)]
+pub struct SidecarConfig {
+  per: Option<Per>,
+  /// Adds the post object as the API returned it under `raw`, default is false.
+  raw: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct Author<'a> {
+  id: &'a str,
+  handle: &'a str,
+  name: &'a str,
+}
+
+#[derive(Serialize)]
+struct Sidecar<'a> {
+  platform: &'a str,
+  url: &'a str,
+  id: &'a str,
+  author: Author<'a>,
+  created_at: Option<DateTime<Utc>>,
+  text: &'a str,
+  hashtags: &'a [String],
+  stats: &'a Stats,
+  /// The downloaded file relative to the account path, only in sidecars per item.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  file: Option<&'a str>,
+  media: &'a [PostMedia],
+  #[serde(skip_serializing_if = "Option::is_none")]
+  raw: Option<&'a Value>,
+}
+
+impl SidecarConfig {
+  /// Writes the sidecar of `item`, saved as `filename` under `dir`.
+  pub async fn write(&self, dir: &str, item: &dyn Item, filename: &str) {
+    let post = item.post();
+    let per = self.per.unwrap_or_default();
+    let (path, file, media) = match per {
+      Per::Item => (
+        Path::new(dir).join(format!("{filename}.json")),
+        Some(filename),
+        post.media.get(item.index() - 1..item.index()).unwrap_or_default(),
+      ),
+      Per::Post => {
+        let parent = Path::new(dir).join(filename).parent().unwrap().to_owned();
+        (parent.join(format!("{} {}.json", post.author, post.id)), None, post.media.as_slice())
+      }
+    };
+    if per == Per::Post && tokio::fs::try_exists(&path).await.unwrap_or(false) {
+      return;
+    }
+
+    let sidecar = Sidecar {
+      platform: post.platform,
+      url: &post.url,
+      id: &post.id,
+      author: Author { id: &post.author_id, handle: &post.author, name: &post.author_name },
+      created_at: post.created_at,
+      text: &post.text,
+      hashtags: &post.hashtags,
+      stats: &post.stats,
+      file,
+      media,
+      raw: self.raw.unwrap_or(false).then_some(&post.raw),
+    };
+    if tokio::fs::write(&path, to_string_pretty(&sidecar).unwrap()).await.is_err() {
+      message!("Cannot create sidecar {}, skipped.", path.display());
+    }
+  }
+}
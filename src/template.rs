@@ -0,0 +1,239 @@
+use chrono::{Datelike, Timelike};
+use serde::{de, Deserialize, Deserializer};
+
+use crate::adapters::Item;
+
+/// Reproduces the names tmd used before templates existed.
+pub const DEFAULT_TEMPLATE: &str = "{author} {id} {index}.{ext}";
+const TEXT_LENGTH: usize = 50;
+
+#[derive(Clone, Copy)]
+enum Field {
+  Platform,
+  Author,
+  AuthorId,
+  Id,
+  Index,
+  Year,
+  Month,
+  Day,
+  Hour,
+  Minute,
+  Second,
+  Kind,
+  Ext,
+  /// The post text in one line, cut to the given number of characters.
+  Text(usize),
+}
+
+enum Part {
+  Literal(String),
+  Field(Field),
+}
+
+/// A path relative to the account path, such as `{author}/{yyyy}-{mm}/{author} {id} {index}.{ext}`.
+///
+/// Fields are `platform`, `author`, `author_id`, `id`, `index`, `yyyy`, `mm`, `dd`, `hh`, `min`,
+/// `ss`, `kind`, `ext` and `text`, which takes an optional length as `{text:80}`. Dates are in UTC.
+pub struct Template {
+  parts: Vec<Part>,
+}
+
+impl Template {
+  pub fn parse(template: &str) -> Result<Self, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+      if start > 0 {
+        parts.push(Part::Literal(rest[..start].to_owned()));
+      }
+      let end = rest[start..].find('}').ok_or(format!("unclosed field in {template}"))? + start;
+      let name = &rest[start + 1..end];
+      let field = match name.split_once(':') {
+        Some(("text", length)) => Field::Text(length.parse().map_err(|_| format!("invalid length in {{{name}}}"))?),
+        Some(_) => return Err(format!("unknown field {{{name}}}")),
+        None => match name {
+          "platform" => Field::Platform,
+          "author" => Field::Author,
+          "author_id" => Field::AuthorId,
+          "id" => Field::Id,
+          "index" => Field::Index,
+          "yyyy" => Field::Year,
+          "mm" => Field::Month,
+          "dd" => Field::Day,
+          "hh" => Field::Hour,
+          "min" => Field::Minute,
+          "ss" => Field::Second,
+          "kind" => Field::Kind,
+          "ext" => Field::Ext,
+          "text" => Field::Text(TEXT_LENGTH),
+          _ => return Err(format!("unknown field {{{name}}}")),
+        },
+      };
+      parts.push(Part::Field(field));
+      rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+      parts.push(Part::Literal(rest.to_owned()));
+    }
+    Ok(Self { parts })
+  }
+
+  /// The path of `item`, with `/` between directories.
+  pub fn render(&self, item: &dyn Item) -> String {
+    let post = item.post();
+    let date = |f: fn(&chrono::DateTime<chrono::Utc>) -> u32, width: usize| {
+      post.created_at.as_ref().map(f).map(|v| format!("{v:0width$}")).unwrap_or("0".repeat(width))
+    };
+    let mut path = String::new();
+    for part in &self.parts {
+      match part {
+        Part::Literal(literal) => path.push_str(literal),
+        Part::Field(field) => path.push_str(&match field {
+          Field::Platform => post.platform.to_owned(),
+          Field::Author => post.author.clone(),
+          Field::AuthorId => post.author_id.clone(),
+          Field::Id => post.id.clone(),
+          Field::Index => item.index().to_string(),
+          Field::Year => date(|v| v.year() as u32, 4),
+          Field::Month => date(|v| v.month(), 2),
+          Field::Day => date(|v| v.day(), 2),
+          Field::Hour => date(|v| v.hour(), 2),
+          Field::Minute => date(|v| v.minute(), 2),
+          Field::Second => date(|v| v.second(), 2),
+          Field::Kind => item.kind().name().to_owned(),
+          Field::Ext => item.ext().to_owned(),
+          Field::Text(length) => snippet(&post.text, *length),
+        }),
+      }
+    }
+    path
+  }
+}
+
+impl Default for Template {
+  fn default() -> Self {
+    Self::parse(DEFAULT_TEMPLATE).unwrap()
+  }
+}
+
+impl<'de> Deserialize<'de> for Template {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    Template::parse(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+  }
+}
+
+/// The post text without links, on one line and without path separators.
+fn snippet(text: &str, length: usize) -> String {
+  let words: Vec<_> = text.split_whitespace().filter(|w| !w.starts_with("http")).collect();
+  words.join(" ")
+    .chars()
+    .map(|c| if matches!(c, '/' | '\\') { '_' } else { c })
+    .take(length)
+    .collect::<String>()
+    .trim()
+    .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use chrono::{DateTime, Utc};
+  use serde_json::Value;
+
+  use super::*;
+  use crate::adapters::{BoxedFuture, MediaKind, Post};
+
+  struct TestItem(Post);
+
+  impl Item for TestItem {
+    fn post(&self) -> &Post {
+      &self.0
+    }
+    fn index(&self) -> usize {
+      2
+    }
+    fn ext(&self) -> &str {
+      "jpg"
+    }
+    fn url(&self) -> &str {
+      &self.0.url
+    }
+    fn media_url(&self) -> &str {
+      ""
+    }
+    fn kind(&self) -> &MediaKind {
+      &MediaKind::Photo
+    }
+    fn alt_text(&self) -> Option<&str> {
+      None
+    }
+    fn post_text(&self) -> &str {
+      &self.0.text
+    }
+    fn timestamp(&self) -> Option<DateTime<Utc>> {
+      self.0.created_at
+    }
+    fn get(&self) -> BoxedFuture<'_, Bytes> {
+      Box::pin(async { Bytes::new() })
+    }
+    fn size(&self) -> BoxedFuture<'_, Option<u64>> {
+      Box::pin(async { None })
+    }
+  }
+
+  fn item(text: &str, created_at: Option<DateTime<Utc>>) -> TestItem {
+    TestItem(Post {
+      platform: "twitter",
+      author: "a".to_owned(),
+      author_id: "10".to_owned(),
+      author_name: "A".to_owned(),
+      id: "1".to_owned(),
+      url: "https://x.com/a/status/1".to_owned(),
+      created_at,
+      text: text.to_owned(),
+      hashtags: Vec::new(),
+      stats: Default::default(),
+      media: Vec::new(),
+      raw: Value::Null,
+    })
+  }
+
+  fn render(template: &str, item: &TestItem) -> String {
+    Template::parse(template).unwrap().render(item)
+  }
+
+  #[test]
+  fn rejects_unknown_and_unclosed_fields() {
+    assert_eq!(Template::parse("{author} {name}").err().unwrap(), "unknown field {name}");
+    assert_eq!(Template::parse("{id:3}").err().unwrap(), "unknown field {id:3}");
+    assert_eq!(Template::parse("{text:many}").err().unwrap(), "invalid length in {text:many}");
+    assert_eq!(Template::parse("{author} {id").err().unwrap(), "unclosed field in {author} {id");
+  }
+
+  #[test]
+  fn renders_fields_and_dates() {
+    let item = item("", DateTime::from_timestamp(1_700_000_000, 0));
+    let path = render("{platform}/{author}-{author_id}/{yyyy}-{mm}-{dd} {hh}{min}{ss} {id} {index} {kind}.{ext}", &item);
+    assert_eq!(path, "twitter/a-10/2023-11-14 221320 1 2 photo.jpg");
+  }
+
+  #[test]
+  fn pads_dates_with_zeros_without_date() {
+    assert_eq!(render("{yyyy}-{mm}-{dd} {hh}{min}{ss}", &item("", None)), "0000-00-00 000000");
+  }
+
+  #[test]
+  fn cuts_text_to_length() {
+    let item = item("one  two\nthree https://t.co/x four", None);
+    assert_eq!(render("{text}", &item), "one two three four");
+    assert_eq!(render("{text:7}", &item), "one two");
+    assert_eq!(render("{text:8}", &item), "one two");
+    assert_eq!(render("{text:0}", &item), "");
+  }
+
+  #[test]
+  fn replaces_separators_in_text() {
+    assert_eq!(render("{author}/{text}", &item("a/b\\c", None)), "a/a_b_c");
+  }
+}